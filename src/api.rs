@@ -1,13 +1,36 @@
 use crate::*;
 use actix_web::{
     delete, get, post,
-    web::{Data, Json, Path, Query},
-    HttpResponse,
+    web::{Data, Json, Path, Payload, Query, ServiceConfig},
+    HttpRequest, HttpResponse, HttpResponseBuilder,
+};
+use actix_ws::Message;
+use anchor_client::{
+    anchor_lang::{AnchorDeserialize, InstructionData},
+    solana_client::rpc_client::GetConfirmedSignaturesForAddress2Config,
+    solana_sdk::{
+        account::from_account,
+        commitment_config::CommitmentLevel,
+        pubkey::Pubkey,
+        signature::Signature,
+        sysvar::{clock, rent},
+        transaction::Transaction,
+    },
+    RequestBuilder,
 };
-use anchor_client::solana_sdk::{pubkey::Pubkey, sysvar::rent};
 use fixed::types::I80F48;
+use futures::StreamExt;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, str::FromStr};
+use solana_transaction_status::UiTransactionEncoding;
+use spl_associated_token_account::create_associated_token_account;
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::broadcast::error::RecvError;
+use utoipa::{IntoParams, OpenApi, ToSchema};
 use zo_abi as zo;
 
 fn div_to_float<T: Into<i128>, U: Into<u32>>(n: T, p: U) -> f64 {
@@ -21,12 +44,99 @@ fn small_to_big<T: Into<u32>>(n: I80F48, decimals: T) -> f64 {
     (n / I80F48::from_num(10u64.pow(decimals.into()))).to_num()
 }
 
-fn big_to_small(n: f64, decimals: u32) -> u64 {
+/// Converts a price in native quote per native base units into USD per
+/// whole base unit.
+fn price_to_big(p: I80F48, asset_decimals: u8) -> f64 {
+    small_to_big(p * I80F48::from_num(10u64.pow(asset_decimals as u32)), 6u8)
+}
+
+fn oracle_price(cache: &zo::Cache, symbol: &str) -> Option<I80F48> {
+    cache
+        .oracle_cache
+        .iter()
+        .find(|o| String::from(o.symbol) == symbol)
+        .map(|o| I80F48::from(o.price))
+}
+
+/// The `name` amount `n` of a token with `decimals`, which must be at least
+/// its smallest unit and fit in a u64 once converted to it.
+fn big_to_small(name: &str, n: f64, decimals: u32) -> Result<u64, Error> {
     let (a, b) = (n as u64, n.rem_euclid(1.));
-    (a * 10u64.pow(decimals)) + (b * 10f64.powi(decimals as i32)) as u64
+    let small = match n < u64::MAX as f64 {
+        true => a.checked_mul(10u64.pow(decimals)).and_then(|a| {
+            a.checked_add((b * 10f64.powi(decimals as i32)) as u64)
+        }),
+        false => None,
+    };
+    match small {
+        Some(0) => Err(Error::InvalidInput(format!(
+            "{} {} is below the smallest unit of the token",
+            name, n
+        ))),
+        Some(x) => Ok(x),
+        None => {
+            Err(Error::InvalidInput(format!("{} {} is too large", name, n)))
+        }
+    }
+}
+
+/// Open interest of a market in base units. The dex market tracks it in base
+/// lots, and the product of two u64s is only guaranteed to fit in an i128.
+fn open_interest(mkt: &zo::dex::ZoDexMarket, decimals: u8) -> f64 {
+    div_to_float(
+        i128::from(mkt.open_interest) * i128::from(mkt.coin_lot_size),
+        decimals,
+    )
 }
 
-#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+/// Limit price, base quantity and quote quantity in lots of an order of
+/// `size` at `price`, which must be at least one tick and one lot.
+fn order_lots(
+    mkt: &zo::dex::ZoDexMarket,
+    price: f64,
+    size: f64,
+) -> Result<(u64, u64, u64), Error> {
+    let limit_price = mkt.price_to_lots(price);
+    let max_base_quantity = mkt.size_to_lots(size);
+    if limit_price == 0 {
+        return Err(Error::InvalidInput(format!(
+            "price {} is below the tick size",
+            price
+        )));
+    }
+    if max_base_quantity == 0 {
+        return Err(Error::InvalidInput(format!(
+            "size {} is below the lot size",
+            size
+        )));
+    }
+    // Conversions to lots saturate, so a maximal one is out of range too.
+    let max_quote_quantity = limit_price
+        .checked_mul(max_base_quantity)
+        .and_then(|x| x.checked_mul(mkt.pc_lot_size))
+        .filter(|_| limit_price < u64::MAX && max_base_quantity < u64::MAX)
+        .ok_or_else(|| {
+            Error::InvalidInput(format!(
+                "order of {} at {} is too large",
+                size, price
+            ))
+        })?;
+    Ok((limit_price, max_base_quantity, max_quote_quantity))
+}
+
+/// Checks that `x`, the field `name` of a request, is a finite number
+/// above zero, before it's converted to native units or lots.
+fn positive(name: &str, x: f64) -> Result<f64, Error> {
+    match x.is_finite() && x > 0. {
+        true => Ok(x),
+        false => Err(Error::InvalidInput(format!(
+            "{} must be a positive number, got {}",
+            name, x
+        ))),
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Copy, PartialEq, Eq)]
 enum Side {
     #[serde(rename = "bid")]
     Bid,
@@ -43,7 +153,7 @@ impl From<zo::dex::Side> for Side {
     }
 }
 
-#[derive(Deserialize, Clone, Copy)]
+#[derive(Deserialize, ToSchema, Clone, Copy)]
 enum OrderType {
     #[serde(rename = "limit")]
     Limit,
@@ -72,7 +182,53 @@ impl From<OrderType> for zo::OrderType {
     }
 }
 
-#[derive(Serialize)]
+#[derive(Deserialize, ToSchema, Clone, Copy, PartialEq, Eq)]
+enum Units {
+    #[serde(rename = "human")]
+    Human,
+    #[serde(rename = "lots")]
+    Lots,
+}
+
+/// Selects the units that order sizes and prices are returned in.
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct UnitsQuery {
+    /// `human` for base units and USD, the default, or `lots` for the
+    /// integer lots the program works in.
+    units: Option<Units>,
+}
+
+/// An amount in human units, or in lots if asked for.
+#[derive(Serialize, Clone, Copy)]
+#[serde(untagged)]
+enum Amount {
+    Human(f64),
+    Lots(u64),
+}
+
+impl ToSchema for Amount {
+    fn schema() -> utoipa::openapi::Schema {
+        use utoipa::openapi::{
+            ObjectBuilder, OneOfBuilder, Schema, SchemaFormat, SchemaType,
+        };
+        let item = |ty, format| {
+            ObjectBuilder::new().schema_type(ty).format(Some(format))
+        };
+        Schema::OneOf(
+            OneOfBuilder::new()
+                .item(item(SchemaType::Number, SchemaFormat::Double))
+                .item(item(SchemaType::Integer, SchemaFormat::Int64))
+                .description(Some(
+                    "A number in human units, or an integer in lots with \
+                     `units=lots`",
+                ))
+                .build(),
+        )
+    }
+}
+
+#[derive(Serialize, ToSchema)]
 #[serde(rename_all = "camelCase")]
 struct Order {
     pub owner_slot: u8,
@@ -80,8 +236,8 @@ struct Order {
     pub control: String,
     pub order_id: u128,
     pub client_order_id: u64,
-    pub size: f64,
-    pub price: f64,
+    pub size: Amount,
+    pub price: Amount,
     pub side: Side,
 }
 
@@ -93,147 +249,727 @@ impl From<zo::dex::Order> for Order {
             control: x.control.to_string(),
             order_id: x.order_id,
             client_order_id: x.client_order_id,
-            size: x.size,
-            price: x.price,
+            size: Amount::Human(x.size),
+            price: Amount::Human(x.price),
             side: x.side.into(),
         }
     }
 }
 
-#[derive(Serialize)]
+impl Order {
+    /// Converts `x` with its size in base lots, `quantity` as stored in
+    /// the slab, and its price in quote lots per base lot.
+    fn lots(x: zo::dex::Order, quantity: u64) -> Self {
+        // The price is kept in the upper half of the order id.
+        let price = (x.order_id >> 64) as u64;
+        Self {
+            size: Amount::Lots(quantity),
+            price: Amount::Lots(price),
+            ..x.into()
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct Book {
+    bids: Vec<Order>,
+    asks: Vec<Order>,
+    /// Lot sizes of the market, to convert amounts in lots back. Only
+    /// with `units=lots`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    coin_lot_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pc_lot_size: Option<u64>,
+}
+
+/// Overrides the commitment that accounts are read at.
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ReadQuery {
+    #[param(value_type = Option<String>)]
+    commitment: Option<CommitmentLevel>,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
 struct SigResp {
     sig: String,
+    confirmed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    compute_units: Option<u64>,
+}
+
+impl From<Sent> for SigResp {
+    fn from(x: Sent) -> Self {
+        Self {
+            sig: x.sig.to_string(),
+            confirmed: x.confirmed,
+            compute_units: x.compute_units,
+        }
+    }
 }
 
+/// Responds with `ok` if the transaction was confirmed, or with 202 if it
+/// was sent but not confirmed in time.
+fn sent_response<T: Serialize>(
+    mut ok: HttpResponseBuilder,
+    confirmed: bool,
+    body: T,
+) -> HttpResponse {
+    match confirmed {
+        true => ok.json(body),
+        false => HttpResponse::Accepted().json(body),
+    }
+}
+
+#[get("/metrics")]
+async fn metrics(st: Data<State>) -> HttpResponse {
+    let gate = st.rpc_gate();
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(format!(
+            "# HELP zo_rpc_concurrency_limit Current limit on in-flight RPC \
+             requests.\n\
+             # TYPE zo_rpc_concurrency_limit gauge\n\
+             zo_rpc_concurrency_limit {}\n\
+             # HELP zo_rpc_in_flight RPC requests currently in flight.\n\
+             # TYPE zo_rpc_in_flight gauge\n\
+             zo_rpc_in_flight {}\n\
+             # HELP zo_pubsub_subscriptions Accounts subscribed to over \
+             websocket.\n\
+             # TYPE zo_pubsub_subscriptions gauge\n\
+             zo_pubsub_subscriptions {}\n",
+            gate.limit(),
+            gate.in_flight(),
+            st.pubsub().subscriptions(),
+        ))
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct Time {
+    /// Unix timestamp of the cluster's clock, in seconds.
+    cluster_time: i64,
+    /// Unix timestamp of the server's clock, in seconds.
+    server_time: i64,
+    /// How far the server's clock is ahead of the cluster's, in seconds.
+    offset: i64,
+    slot: u64,
+}
+
+#[utoipa::path(
+    params(ReadQuery),
+    responses((status = 200, body = Time))
+)]
+#[get("/time")]
+async fn time(
+    st: Data<State>,
+    c: Query<ReadQuery>,
+) -> Result<Json<Time>, Error> {
+    let st = st.with_read_commitment(c.commitment);
+    let clock = st
+        .with_rpc(|st| {
+            st.account(&clock::ID)?
+                .and_then(|a| from_account::<clock::Clock, _>(&a))
+                .ok_or_else(|| {
+                    anchor_client::ClientError::AccountNotFound.into()
+                })
+        })
+        .await?;
+    let server_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    Ok(Json(Time {
+        cluster_time: clock.unix_timestamp,
+        server_time,
+        offset: server_time - clock.unix_timestamp,
+        slot: clock.slot,
+    }))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct OwnerQuery {
+    /// Read the account of this wallet instead of the service's own.
+    owner: Option<String>,
+}
+
+impl OwnerQuery {
+    fn owner(&self) -> Result<Option<Pubkey>, Error> {
+        Ok(match self.owner {
+            Some(ref s) => Some(Pubkey::from_str(s)?),
+            None => None,
+        })
+    }
+}
+
+/// How close the account is to liquidation.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct Health {
+    /// Equity over notional, null without positions.
+    margin_fraction: Option<f64>,
+    /// Margin fraction below which the account can be liquidated, the
+    /// positions' maintenance margin fractions weighted by notional. Null
+    /// without positions.
+    maintenance_margin_fraction: Option<f64>,
+    /// Whether the margin fraction is within the warning threshold of the
+    /// maintenance margin fraction.
+    health_warning: bool,
+}
+
+fn health(st: &State, v: &AccountValue) -> Health {
+    let fraction = |x: f64| (v.notional > 0.).then(|| x / v.notional);
+    let margin_fraction = fraction(v.equity);
+    let maintenance_margin_fraction = fraction(v.maintenance);
+    Health {
+        margin_fraction,
+        maintenance_margin_fraction,
+        health_warning: margin_fraction
+            .zip(maintenance_margin_fraction)
+            .map_or(false, |(x, mmf)| x < mmf + st.health_warning_threshold),
+    }
+}
+
+/// Health of the account in a snapshot for reads that don't need its
+/// value otherwise, which is `None` rather than an error if a collateral
+/// it holds has no oracle price.
+fn snapshot_health(st: &State, snapshot: &Snapshot) -> Option<Health> {
+    account_value(st, snapshot).ok().map(|v| health(st, &v))
+}
+
+/// Opts into reading the account's health along with its balances or
+/// positions, which wraps them in an object.
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct HealthQuery {
+    /// Return `{ "balances" | "positions": ..., "health": ... }` instead of
+    /// the plain map.
+    #[serde(default)]
+    health: bool,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct BalancesQuery {
+    /// Return a breakdown of each balance instead of the net amount.
+    #[serde(default)]
+    detailed: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct BalancesResp {
+    /// Net balance of each collateral, negative when borrowed.
+    balances: HashMap<String, f64>,
+    /// Breakdown of each balance, only with `detailed`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detailed: Option<HashMap<String, CollateralBalance>>,
+    /// Null if a collateral the account holds has no oracle price.
+    health: Option<Health>,
+}
+
+/// A collateral balance, which is either a deposit or a borrow.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct CollateralBalance {
+    deposited: f64,
+    borrowed: f64,
+    /// Deposited less borrowed.
+    net: f64,
+    /// Interest multiplier applied to the balance, the supply multiplier
+    /// for deposits and the borrow multiplier for borrows.
+    multiplier: f64,
+    /// Oracle price in USD, null if the oracle has none.
+    price: Option<f64>,
+    /// Value of the net balance in USD, null without a price.
+    usd_value: Option<f64>,
+}
+
+#[utoipa::path(
+    params(OwnerQuery, ReadQuery, BalancesQuery, HealthQuery),
+    responses(
+        (
+            status = 200,
+            body = HashMap<String, f64>,
+            description = "Net balances, a `CollateralBalance` per \
+                collateral with `detailed`, or a `BalancesResp` with `health`"
+        ),
+        (status = 404, description = "No margin account")
+    )
+)]
 #[get("/collateral/balances")]
 async fn collateral_balances(
     st: Data<State>,
-) -> Result<Json<HashMap<String, f64>>, Error> {
-    let (cache, margin) = tokio::try_join!(st.zo_cache(), st.zo_margin())?;
-    let r = st
-        .zo_collaterals()
+    o: Query<OwnerQuery>,
+    c: Query<ReadQuery>,
+    q: Query<BalancesQuery>,
+    h: Query<HealthQuery>,
+) -> Result<HttpResponse, Error> {
+    let st = st.with_read_commitment(c.commitment).with_owner(o.owner()?);
+    let snapshot = st.snapshot().await?;
+    let detailed = balances(&st, &snapshot);
+    let mut res = HttpResponse::Ok();
+    Ok(match (h.health, q.detailed) {
+        (false, false) => res.json(net_balances(&detailed)),
+        (false, true) => res.json(detailed),
+        (true, _) => res.json(BalancesResp {
+            balances: net_balances(&detailed),
+            detailed: q.detailed.then(|| detailed),
+            health: snapshot_health(&st, &snapshot),
+        }),
+    })
+}
+
+fn balances(
+    st: &State,
+    snapshot: &Snapshot,
+) -> HashMap<String, CollateralBalance> {
+    let Snapshot { cache, margin, .. } = snapshot;
+    st.zo_collaterals()
         .enumerate()
         .map(|(i, c)| {
+            let symbol = String::from(c.oracle_symbol);
             let collat = I80F48::from(margin.collateral[i]);
             let mult = I80F48::from(match collat >= I80F48::ZERO {
                 true => cache.borrow_cache[i].supply_multiplier,
                 false => cache.borrow_cache[i].borrow_multiplier,
             });
+            let price = oracle_price(cache, &symbol);
+            let net = small_to_big(collat * mult, c.decimals);
+            let balance = CollateralBalance {
+                deposited: net.max(0.),
+                borrowed: (-net).max(0.),
+                net,
+                multiplier: mult.to_num(),
+                price: price.map(|p| price_to_big(p, c.decimals)),
+                usd_value: price.map(|p| small_to_big(collat * mult * p, 6u8)),
+            };
+            (symbol, balance)
+        })
+        .collect()
+}
+
+fn net_balances(
+    r: &HashMap<String, CollateralBalance>,
+) -> HashMap<String, f64> {
+    r.iter().map(|(k, v)| (k.clone(), v.net)).collect()
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct CollateralRates {
+    supply_apy: f64,
+    borrow_apy: f64,
+    utilization: f64,
+    supply_multiplier: f64,
+    borrow_multiplier: f64,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct HistoryQuery {
+    /// Number of the margin account's latest transactions to look through,
+    /// 20 by default, at least 1 and at most 100.
+    limit: Option<usize>,
+}
+
+/// Most transactions `GET /collateral/history` looks through, as each one
+/// has to be fetched separately.
+const HISTORY_LIMIT: usize = 100;
+
+#[derive(Serialize, ToSchema, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum CollateralEventKind {
+    Deposit,
+    Withdraw,
+}
+
+#[derive(Serialize, ToSchema)]
+struct CollateralEvent {
+    signature: String,
+    #[serde(rename = "type")]
+    kind: CollateralEventKind,
+    symbol: String,
+    /// Amount requested, which for repay-only deposits may be more than
+    /// was actually moved.
+    amount: f64,
+    /// Unix timestamp of the block, if the node knows it.
+    timestamp: Option<i64>,
+}
+
+/// Deposits and withdrawals among the top-level instructions of `tx`.
+fn collateral_events(
+    st: &State,
+    tx: &Transaction,
+    signature: &str,
+    timestamp: Option<i64>,
+) -> Vec<CollateralEvent> {
+    let deposit = zo::instruction::Deposit {
+        repay_only: false,
+        amount: 0,
+    }
+    .data();
+    let withdraw = zo::instruction::Withdraw {
+        allow_borrow: false,
+        amount: 0,
+    }
+    .data();
+    let keys = &tx.message.account_keys;
+    tx.message
+        .instructions
+        .iter()
+        .filter(|ix| keys[ix.program_id_index as usize] == zo::ID)
+        .filter_map(|ix| {
+            let (tag, args) = ix.data.split_at(ix.data.len().min(8));
+            let (kind, amount) = if tag == &deposit[..8] {
+                let x = zo::instruction::Deposit::try_from_slice(args).ok()?;
+                (CollateralEventKind::Deposit, x.amount)
+            } else if tag == &withdraw[..8] {
+                let x = zo::instruction::Withdraw::try_from_slice(args).ok()?;
+                (CollateralEventKind::Withdraw, x.amount)
+            } else {
+                return None;
+            };
+            // The collateral is told apart by the vault the instruction
+            // moves funds to or from.
+            let c = st
+                .zo_collaterals()
+                .zip(st.zo_state().vaults.iter())
+                .find(|(_, v)| {
+                    ix.accounts.iter().any(|&i| keys[i as usize] == **v)
+                })?
+                .0;
+            Some(CollateralEvent {
+                signature: signature.to_owned(),
+                kind,
+                symbol: String::from(c.oracle_symbol),
+                amount: div_to_float(amount, c.decimals),
+                timestamp,
+            })
+        })
+        .collect()
+}
+
+#[utoipa::path(
+    params(OwnerQuery, HistoryQuery),
+    responses(
+        (status = 200, body = [CollateralEvent]),
+        (status = 404, description = "No margin account")
+    )
+)]
+#[get("/collateral/history")]
+async fn collateral_history(
+    st: Data<State>,
+    o: Query<OwnerQuery>,
+    q: Query<HistoryQuery>,
+) -> Result<Json<Vec<CollateralEvent>>, Error> {
+    let st = st.with_owner(o.owner()?);
+    // Fails with 404 if there's no margin account to look through.
+    st.zo_margin().await?;
+    let limit = q.limit.unwrap_or(20).clamp(1, HISTORY_LIMIT);
+    let sigs = st
+        .with_rpc(move |st| {
+            Ok(st.rpc().get_signatures_for_address_with_config(
+                &st.zo_margin_key,
+                GetConfirmedSignaturesForAddress2Config {
+                    limit: Some(limit),
+                    ..Default::default()
+                },
+            )?)
+        })
+        .await?;
+    // Failed transactions didn't move anything. The rest are fetched
+    // concurrently, each through the RPC gate on its own.
+    let txs = futures::future::try_join_all(
+        sigs.into_iter()
+            .filter(|s| s.err.is_none())
+            .filter_map(|s| Some((Signature::from_str(&s.signature).ok()?, s)))
+            .map(|(sig, s)| {
+                st.with_rpc(move |st| {
+                    let tx = st
+                        .rpc()
+                        .get_transaction(&sig, UiTransactionEncoding::Base64)?;
+                    Ok((s, tx))
+                })
+            }),
+    )
+    .await?;
+    let r = txs
+        .into_iter()
+        .filter_map(|(s, tx)| {
+            let tx = tx.transaction.transaction.decode()?;
+            Some(collateral_events(&st, &tx, &s.signature, s.block_time))
+        })
+        .flatten()
+        .collect();
+    Ok(Json(r))
+}
+
+#[utoipa::path(
+    params(ReadQuery),
+    responses((status = 200, body = HashMap<String, CollateralRates>))
+)]
+#[get("/collateral/rates")]
+async fn collateral_rates(
+    st: Data<State>,
+    c: Query<ReadQuery>,
+) -> Result<Json<HashMap<String, CollateralRates>>, Error> {
+    let st = st.with_read_commitment(c.commitment);
+    let cache = st.zo_cache().await?;
+    let r = st
+        .zo_collaterals()
+        .zip(cache.borrow_cache.iter())
+        .map(|(c, b)| {
+            let supply_mult = I80F48::from(b.supply_multiplier);
+            let borrow_mult = I80F48::from(b.borrow_multiplier);
+            let supply = I80F48::from(b.supply) * supply_mult;
+            let borrows = I80F48::from(b.borrows) * borrow_mult;
+            let util: f64 = match supply > I80F48::ZERO {
+                true => (borrows / supply).to_num(),
+                false => 0.,
+            };
+
+            // Interest rates follow a kinked curve, with the parameters in
+            // thousandths.
+            let optimal_util = c.optimal_util as f64 / 1000.;
+            let optimal_rate = c.optimal_rate as f64 / 1000.;
+            let max_rate = c.max_rate as f64 / 1000.;
+            // The kink may sit at either end of the curve, leaving one of
+            // the segments empty.
+            let borrow_rate = match util {
+                u if u <= optimal_util && optimal_util > 0. => {
+                    u / optimal_util * optimal_rate
+                }
+                u if optimal_util < 1. => {
+                    optimal_rate
+                        + (u - optimal_util).max(0.) / (1. - optimal_util)
+                            * (max_rate - optimal_rate)
+                }
+                _ => max_rate,
+            };
+            let supply_rate = borrow_rate * util;
+
+            // Interest accrues continuously through the multipliers.
             (
                 String::from(c.oracle_symbol),
-                small_to_big(collat * mult, c.decimals),
+                CollateralRates {
+                    supply_apy: supply_rate.exp_m1(),
+                    borrow_apy: borrow_rate.exp_m1(),
+                    utilization: util,
+                    supply_multiplier: supply_mult.to_num(),
+                    borrow_multiplier: borrow_mult.to_num(),
+                },
             )
         })
         .collect();
-
     Ok(Json(r))
 }
 
-#[derive(Deserialize)]
+/// Rejects token accounts that aren't `owner`'s accounts for the given
+/// mint, so that funds aren't moved through the wrong account.
+async fn check_token_account(
+    st: &State,
+    k: Pubkey,
+    mint: Pubkey,
+    owner: Pubkey,
+) -> Result<(), Error> {
+    let x = st.token_account(k).await?.ok_or_else(|| {
+        Error::InvalidTokenAccount(format!("{} is not a token account", k))
+    })?;
+    if x.mint != mint {
+        return Err(Error::InvalidTokenAccount(format!(
+            "{} is for mint {}, expected {}",
+            k, x.mint, mint
+        )));
+    }
+    if x.owner != owner {
+        return Err(Error::InvalidTokenAccount(format!(
+            "{} is owned by {}, expected {}",
+            k, x.owner, owner
+        )));
+    }
+    Ok(())
+}
+
+#[derive(Deserialize, ToSchema)]
 #[serde(rename_all = "camelCase")]
 struct CollateralDepositQuery {
     #[serde(default)]
     repay_only: bool,
     amount: f64,
     token_account: Option<String>,
+    #[serde(flatten)]
+    send: SendOptions,
 }
 
+#[utoipa::path(
+    params(("symbol" = String, Path)),
+    request_body = CollateralDepositQuery,
+    responses(
+        (status = 200, body = SigResp),
+        (status = 202, body = SigResp, description = "Unconfirmed")
+    )
+)]
 #[post("/collateral/deposit/{symbol}")]
 async fn collateral_deposit(
     st: Data<State>,
-    s: Path<String>,
+    s: Symbol,
     q: Json<CollateralDepositQuery>,
-) -> Result<Json<SigResp>, Error> {
+) -> Result<HttpResponse, Error> {
+    positive("amount", q.amount)?;
     let collateral = st.collateral(&s)?;
     let vault = *st.vault(&s)?;
-    let decimals = collateral.decimals as u32;
+    let amount = big_to_small("amount", q.amount, collateral.decimals as u32)?;
     let token_account = match q.token_account {
-        Some(ref s) => Pubkey::from_str(s)?,
+        Some(ref s) => {
+            let k = Pubkey::from_str(s)?;
+            check_token_account(&st, k, collateral.mint, st.authority())
+                .await?;
+            k
+        }
         None => anchor_spl::associated_token::get_associated_token_address(
             &st.authority(),
             &collateral.mint,
         ),
     };
-    let st = st.clone();
-    let sig = tokio::task::spawn_blocking(move || {
-        st.program()
-            .request()
-            .args(zo::instruction::Deposit {
-                repay_only: q.repay_only,
-                amount: big_to_small(q.amount, decimals),
-            })
-            .accounts(zo::accounts::Deposit {
-                state: zo::ZO_STATE_ID,
-                state_signer: st.zo_state_signer,
-                cache: st.zo_state().cache,
-                authority: st.authority(),
-                margin: st.zo_margin_key,
-                token_account,
-                vault,
-                token_program: anchor_spl::token::ID,
-            })
-            .send()
-    })
-    .await
-    .unwrap()?
-    .to_string();
-    Ok(Json(SigResp { sig }))
+    let sent = st
+        .with_rpc(move |st| {
+            st.send(
+                st.program()
+                    .request()
+                    .args(zo::instruction::Deposit {
+                        repay_only: q.repay_only,
+                        amount,
+                    })
+                    .accounts(zo::accounts::Deposit {
+                        state: zo::ZO_STATE_ID,
+                        state_signer: st.zo_state_signer,
+                        cache: st.zo_state().cache,
+                        authority: st.authority(),
+                        margin: st.zo_margin_key,
+                        token_account,
+                        vault,
+                        token_program: anchor_spl::token::ID,
+                    }),
+                q.send,
+            )
+        })
+        .await?;
+    Ok(sent_response(
+        HttpResponse::Ok(),
+        sent.confirmed,
+        SigResp::from(sent),
+    ))
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 #[serde(rename_all = "camelCase")]
 struct CollateralWithdrawQuery {
     #[serde(default)]
     allow_borrow: bool,
     amount: f64,
     token_account: Option<String>,
+    /// Withdraw to this wallet's associated token account, creating it if
+    /// it doesn't exist.
+    destination_owner: Option<String>,
+    #[serde(flatten)]
+    send: SendOptions,
 }
 
+#[utoipa::path(
+    params(("symbol" = String, Path)),
+    request_body = CollateralWithdrawQuery,
+    responses(
+        (status = 200, body = SigResp),
+        (status = 202, body = SigResp, description = "Unconfirmed")
+    )
+)]
 #[post("/collateral/withdraw/{symbol}")]
 async fn collateral_withdraw(
     st: Data<State>,
-    s: Path<String>,
+    s: Symbol,
     q: Json<CollateralWithdrawQuery>,
-) -> Result<Json<SigResp>, Error> {
+) -> Result<HttpResponse, Error> {
+    positive("amount", q.amount)?;
     let collateral = st.collateral(&s)?;
     let vault = *st.vault(&s)?;
-    let decimals = collateral.decimals as u32;
-    let token_account = match q.token_account {
-        Some(ref s) => Pubkey::from_str(s)?,
-        None => anchor_spl::associated_token::get_associated_token_address(
-            &st.authority(),
-            &collateral.mint,
+    let amount = big_to_small("amount", q.amount, collateral.decimals as u32)?;
+    let mint = collateral.mint;
+    let (account, owner) = (&q.token_account, &q.destination_owner);
+    if account.is_some() && owner.is_some() {
+        return Err(Error::InvalidInput(
+            "tokenAccount conflicts with destinationOwner, give only one"
+                .to_owned(),
+        ));
+    }
+    let (token_account, create) = match (account, owner) {
+        (Some(s), _) => {
+            let k = Pubkey::from_str(s)?;
+            check_token_account(&st, k, mint, st.authority()).await?;
+            (k, None)
+        }
+        (None, Some(s)) => {
+            let owner = Pubkey::from_str(s)?;
+            let k = anchor_spl::associated_token::get_associated_token_address(
+                &owner, &mint,
+            );
+            // The address is derived from the owner and mint, so an
+            // existing account there is the right one.
+            match st.token_account(k).await? {
+                Some(_) => (k, None),
+                None => (k, Some(owner)),
+            }
+        }
+        (None, None) => (
+            anchor_spl::associated_token::get_associated_token_address(
+                &st.authority(),
+                &mint,
+            ),
+            None,
         ),
     };
     let margin = st.zo_margin().await?;
-    let st = st.clone();
-    let sig = tokio::task::spawn_blocking(move || {
-        st.program()
-            .request()
-            .args(zo::instruction::Withdraw {
-                allow_borrow: q.allow_borrow,
-                amount: big_to_small(q.amount, decimals),
-            })
-            .accounts(zo::accounts::Withdraw {
-                state: zo::ZO_STATE_ID,
-                state_signer: st.zo_state_signer,
-                cache: st.zo_state().cache,
-                authority: st.authority(),
-                margin: st.zo_margin_key,
-                control: margin.control,
-                token_account,
-                vault,
-                token_program: anchor_spl::token::ID,
-            })
-            .send()
-    })
-    .await
-    .unwrap()?
-    .to_string();
-    Ok(Json(SigResp { sig }))
+    let sent = st
+        .with_rpc(move |st| {
+            let mut req = st.program().request();
+            if let Some(owner) = create {
+                req = req.instruction(create_associated_token_account(
+                    &st.authority(),
+                    &owner,
+                    &mint,
+                ));
+            }
+            st.send(
+                req.args(zo::instruction::Withdraw {
+                    allow_borrow: q.allow_borrow,
+                    amount,
+                })
+                .accounts(zo::accounts::Withdraw {
+                    state: zo::ZO_STATE_ID,
+                    state_signer: st.zo_state_signer,
+                    cache: st.zo_state().cache,
+                    authority: st.authority(),
+                    margin: st.zo_margin_key,
+                    control: margin.control,
+                    token_account,
+                    vault,
+                    token_program: anchor_spl::token::ID,
+                }),
+                q.send,
+            )
+        })
+        .await?;
+    Ok(sent_response(
+        HttpResponse::Ok(),
+        sent.confirmed,
+        SigResp::from(sent),
+    ))
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 #[serde(rename_all = "camelCase")]
 struct PositionInfo {
     size: f64,
@@ -243,17 +979,40 @@ struct PositionInfo {
     is_long: bool,
 }
 
-#[get("/position")]
-async fn position(
-    st: Data<State>,
-) -> Result<Json<HashMap<String, PositionInfo>>, Error> {
-    let (_, control) = st.trader_accounts().await?;
-    let r = st
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct PositionQuery {
+    /// Comma-separated market symbols to return, all markets if omitted.
+    symbols: Option<String>,
+}
+
+/// Positions of the margin account in a snapshot, optionally only in the
+/// comma-separated `symbols`.
+fn positions(
+    st: &State,
+    snapshot: &Snapshot,
+    symbols: Option<&str>,
+) -> Result<HashMap<String, PositionInfo>, Error> {
+    let control = &snapshot.control;
+    // Symbols are matched case-insensitively, so the market's own
+    // spelling is what's filtered on.
+    let symbols = match symbols {
+        Some(x) => Some(
+            x.split(',')
+                .map(|s| {
+                    let s: Symbol = s.parse()?;
+                    Ok(String::from(st.market(&s)?.symbol))
+                })
+                .collect::<Result<HashSet<_>, Error>>()?,
+        ),
+        None => None,
+    };
+    Ok(st
         .zo_markets()
         .zip(control.open_orders_agg.iter())
         .map(|(mkt, oo)| {
             (
-                mkt.symbol.into(),
+                String::from(mkt.symbol),
                 match oo.key == Pubkey::default() {
                     true => PositionInfo {
                         size: 0.,
@@ -276,32 +1035,861 @@ async fn position(
                 },
             )
         })
+        .filter(|(s, _)| symbols.as_ref().map_or(true, |x| x.contains(s)))
+        .collect())
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct PositionsResp {
+    positions: HashMap<String, PositionInfo>,
+    /// Null if a collateral the account holds has no oracle price.
+    health: Option<Health>,
+}
+
+/// Responds with the positions in `snapshot`, wrapped along with the
+/// account's health if asked for.
+fn positions_response(
+    st: &State,
+    snapshot: &Snapshot,
+    symbols: Option<&str>,
+    with_health: bool,
+) -> Result<HttpResponse, Error> {
+    let positions = positions(st, snapshot, symbols)?;
+    let mut res = HttpResponse::Ok();
+    Ok(match with_health {
+        true => res.json(PositionsResp {
+            positions,
+            health: snapshot_health(st, snapshot),
+        }),
+        false => res.json(positions),
+    })
+}
+
+#[utoipa::path(
+    params(PositionQuery, OwnerQuery, ReadQuery, HealthQuery),
+    responses(
+        (
+            status = 200,
+            body = HashMap<String, PositionInfo>,
+            description = "Positions, or a `PositionsResp` with `health`"
+        ),
+        (status = 404, description = "No margin account")
+    )
+)]
+#[get("/position")]
+async fn position(
+    st: Data<State>,
+    q: Query<PositionQuery>,
+    o: Query<OwnerQuery>,
+    c: Query<ReadQuery>,
+    h: Query<HealthQuery>,
+) -> Result<HttpResponse, Error> {
+    let st = st.with_read_commitment(c.commitment).with_owner(o.owner()?);
+    let snapshot = st.snapshot().await?;
+    positions_response(&st, &snapshot, q.symbols.as_deref(), h.health)
+}
+
+#[utoipa::path(
+    params(("pubkey" = String, Path), PositionQuery, ReadQuery, HealthQuery),
+    responses(
+        (
+            status = 200,
+            body = HashMap<String, PositionInfo>,
+            description = "Positions, or a `PositionsResp` with `health`"
+        ),
+        (status = 404, description = "No margin account")
+    )
+)]
+#[get("/position/by-owner/{pubkey}")]
+async fn position_by_owner(
+    st: Data<State>,
+    owner: Path<String>,
+    q: Query<PositionQuery>,
+    c: Query<ReadQuery>,
+    h: Query<HealthQuery>,
+) -> Result<HttpResponse, Error> {
+    let owner = Pubkey::from_str(&owner)?;
+    let st = st
+        .with_read_commitment(c.commitment)
+        .with_owner(Some(owner));
+    let snapshot = st.snapshot().await?;
+    positions_response(&st, &snapshot, q.symbols.as_deref(), h.health)
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct MarketLimits {
+    /// Null if the market has no initial margin requirement.
+    max_leverage: Option<f64>,
+    max_position_size: Option<f64>,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct AccountInfo {
+    equity: f64,
+    notional: f64,
+    current_leverage: f64,
+    #[serde(flatten)]
+    health: Health,
+    markets: HashMap<String, MarketLimits>,
+    /// Same as `GET /position`.
+    positions: HashMap<String, PositionInfo>,
+    /// Same as `GET /collateral/balances`.
+    balances: HashMap<String, f64>,
+    /// Slot that everything was read at.
+    slot: u64,
+}
+
+/// Value of the margin account in a snapshot, in USD.
+struct AccountValue {
+    equity: f64,
+    /// Total notional of the positions.
+    notional: f64,
+    /// Margin the positions need to stay clear of liquidation.
+    maintenance: f64,
+}
+
+/// Values the margin account in a snapshot. Fails if a collateral the
+/// account holds has no oracle price, rather than understating the equity.
+fn account_value(
+    st: &State,
+    snapshot: &Snapshot,
+) -> Result<AccountValue, Error> {
+    let Snapshot {
+        cache,
+        margin,
+        control,
+        ..
+    } = snapshot;
+
+    let collateral = st
+        .zo_collaterals()
+        .enumerate()
+        .filter(|&(i, _)| I80F48::from(margin.collateral[i]) != I80F48::ZERO)
+        .map(|(i, c)| {
+            let collat = I80F48::from(margin.collateral[i]);
+            let mult = I80F48::from(match collat >= I80F48::ZERO {
+                true => cache.borrow_cache[i].supply_multiplier,
+                false => cache.borrow_cache[i].borrow_multiplier,
+            });
+            let symbol = String::from(c.oracle_symbol);
+            let price = oracle_price(cache, &symbol)
+                .ok_or(Error::PriceNotFound(symbol))?;
+            Ok(collat * mult * price)
+        })
+        .sum::<Result<I80F48, Error>>()?;
+
+    // Notional and unrealized pnl are both valued at the mark price.
+    let zero = I80F48::ZERO;
+    let (notional, pnl, maintenance) = st
+        .zo_markets()
+        .zip(control.open_orders_agg.iter())
+        .zip(cache.marks.iter())
+        .filter(|((_, oo), _)| oo.key != Pubkey::default())
+        .fold((zero, zero, zero), |(n, p, m), ((mkt, oo), mark)| {
+            let (pos_size, pc_total) = (oo.pos_size, oo.native_pc_total);
+            let value =
+                I80F48::from_num(pos_size).abs() * I80F48::from(mark.price);
+            let cost = I80F48::from_num(pc_total).abs();
+            let pnl = match pos_size >= 0 {
+                true => value - cost,
+                false => cost - value,
+            };
+            // The maintenance margin fraction is in thousandths.
+            let mmf = I80F48::from_num(mkt.base_mmf) / I80F48::from_num(1000);
+            (n + value, p + pnl, m + value * mmf)
+        });
+
+    Ok(AccountValue {
+        equity: small_to_big(collateral + pnl, 6u8),
+        notional: small_to_big(notional, 6u8),
+        maintenance: small_to_big(maintenance, 6u8),
+    })
+}
+
+/// None if the market has no initial margin requirement.
+fn max_leverage(mkt: &zo::PerpMarketInfo) -> Option<f64> {
+    // The initial margin fraction is in thousandths.
+    let imf = mkt.base_imf;
+    (imf > 0).then(|| 1000. / imf as f64)
+}
+
+/// Largest position `equity` supports at `max_leverage` and `price`.
+fn max_position_size(equity: f64, max_leverage: f64, price: f64) -> f64 {
+    match price > 0. {
+        true => (equity * max_leverage / price).max(0.),
+        false => 0.,
+    }
+}
+
+#[utoipa::path(
+    params(ReadQuery),
+    responses((status = 200, body = AccountInfo))
+)]
+#[get("/account")]
+async fn account(
+    st: Data<State>,
+    c: Query<ReadQuery>,
+) -> Result<Json<AccountInfo>, Error> {
+    let st = st.with_read_commitment(c.commitment);
+    let snapshot = st.snapshot().await?;
+    let value = account_value(&st, &snapshot)?;
+    let (equity, notional) = (value.equity, value.notional);
+    let markets = st
+        .zo_markets()
+        .zip(snapshot.cache.marks.iter())
+        .map(|(mkt, mark)| {
+            let max_leverage = max_leverage(mkt);
+            let price = price_to_big(mark.price.into(), mkt.asset_decimals);
+            (
+                String::from(mkt.symbol),
+                MarketLimits {
+                    max_leverage,
+                    max_position_size: max_leverage
+                        .map(|l| max_position_size(equity, l, price)),
+                },
+            )
+        })
         .collect();
-    Ok(Json(r))
+
+    Ok(Json(AccountInfo {
+        equity,
+        notional,
+        current_leverage: match equity > 0. {
+            true => notional / equity,
+            false => 0.,
+        },
+        health: health(&st, &value),
+        markets,
+        positions: positions(&st, &snapshot, None)?,
+        balances: net_balances(&balances(&st, &snapshot)),
+        slot: snapshot.slot,
+    }))
+}
+
+async fn book(
+    st: &State,
+    s: &str,
+    units: Option<Units>,
+) -> Result<Book, Error> {
+    let mkt = st.dex_market(s).await?;
+    let (bids, asks) = tokio::try_join!(st.slab(mkt.bids), st.slab(mkt.asks))?;
+    let lots = units == Some(Units::Lots);
+    let orders = |slab: &zo::dex::Slab, side| {
+        slab.iter_front()
+            .map(|o| match lots {
+                true => Order::lots(mkt.parse_order(o, side), o.quantity()),
+                false => mkt.parse_order(o, side).into(),
+            })
+            .collect()
+    };
+    Ok(Book {
+        bids: orders(&bids, zo::dex::Side::Bid),
+        asks: orders(&asks, zo::dex::Side::Ask),
+        coin_lot_size: lots.then(|| mkt.coin_lot_size),
+        pc_lot_size: lots.then(|| mkt.pc_lot_size),
+    })
 }
 
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct OrdersMultiQuery {
+    symbols: String,
+}
+
+#[utoipa::path(
+    params(OrdersMultiQuery, UnitsQuery, ReadQuery),
+    responses((status = 200, body = HashMap<String, Book>))
+)]
+#[get("/orders")]
+async fn orders_multi(
+    st: Data<State>,
+    q: Query<OrdersMultiQuery>,
+    u: Query<UnitsQuery>,
+    c: Query<ReadQuery>,
+) -> Result<Json<HashMap<String, Book>>, Error> {
+    let st = st.with_read_commitment(c.commitment);
+    let symbols = q
+        .symbols
+        .split(',')
+        .map(str::parse)
+        .collect::<Result<Vec<Symbol>, _>>()?;
+    let books = futures::future::try_join_all(
+        symbols.iter().map(|s| book(&st, s, u.units)),
+    )
+    .await?;
+    Ok(Json(
+        symbols.into_iter().map(String::from).zip(books).collect(),
+    ))
+}
+
+#[utoipa::path(
+    params(OwnerQuery, ReadQuery),
+    responses(
+        (status = 200, body = HashMap<String, Vec<Order>>),
+        (status = 404, description = "No margin account")
+    )
+)]
+#[get("/orders/open")]
+async fn orders_open(
+    st: Data<State>,
+    o: Query<OwnerQuery>,
+    c: Query<ReadQuery>,
+) -> Result<Json<HashMap<String, Vec<Order>>>, Error> {
+    let st = st.with_read_commitment(c.commitment).with_owner(o.owner()?);
+    let (margin, control) = st.trader_accounts().await?;
+    // Only markets with resting orders need their books read.
+    let symbols: Vec<String> = st
+        .zo_markets()
+        .zip(control.open_orders_agg.iter())
+        .filter(|(_, oo)| oo.key != Pubkey::default() && oo.order_count > 0)
+        .map(|(m, _)| String::from(m.symbol))
+        .collect();
+    let orders = futures::future::try_join_all(symbols.iter().map(|s| {
+        let st = &st;
+        async move {
+            let mkt = st.dex_market(s).await?;
+            let (bids, asks) =
+                tokio::try_join!(st.slab(mkt.bids), st.slab(mkt.asks))?;
+            Ok::<_, Error>(
+                bids.iter_front()
+                    .map(|o| mkt.parse_order(o, zo::dex::Side::Bid))
+                    .chain(
+                        asks.iter_front()
+                            .map(|o| mkt.parse_order(o, zo::dex::Side::Ask)),
+                    )
+                    .filter(|o| o.control == margin.control)
+                    .map(Order::from)
+                    .collect::<Vec<_>>(),
+            )
+        }
+    }))
+    .await?;
+    Ok(Json(symbols.into_iter().zip(orders).collect()))
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct PositionCloseResp {
+    sig: String,
+    confirmed: bool,
+    /// Size filled by the closing order, null if the transaction wasn't
+    /// confirmed in time or the position couldn't be read after it.
+    closed: Option<f64>,
+    /// Size of the position left open.
+    remaining: Option<f64>,
+    /// Average fill price in USD, null without fills or if the crank
+    /// consumed them before they were read.
+    price: Option<f64>,
+}
+
+/// Sequence numbers of the events in the event queue at `k` and its fills,
+/// read at the latest state. `None` if the queue can't be decoded.
+async fn event_queue(
+    st: &State,
+    k: Pubkey,
+) -> Result<Option<(Range<u64>, Vec<Fill>)>, Error> {
+    let a = st
+        .with_read_commitment(Some(CommitmentLevel::Processed))
+        .with_rpc(move |st| st.account(&k))
+        .await?;
+    Ok(a.and_then(|a| event_queue_fills(&a.data)))
+}
+
+#[utoipa::path(
+    params(("symbol" = String, Path)),
+    responses(
+        (status = 200, body = PositionCloseResp),
+        (status = 202, body = PositionCloseResp, description = "Unconfirmed"),
+        (status = 404, description = "No open position")
+    )
+)]
+#[post("/position/{symbol}/close")]
+async fn position_close(
+    st: Data<State>,
+    s: Symbol,
+) -> Result<HttpResponse, Error> {
+    let i = st.market_symbol_index(&s)?;
+    let decimals = st.market(&s)?.asset_decimals;
+    let mkt = st.dex_market(&s).await?;
+    let open_orders = st.oo(&s).await?;
+    let Snapshot {
+        margin, control, ..
+    } = st.snapshot().await?;
+    let pos_size = control.open_orders_agg[i].pos_size;
+    if pos_size == 0 {
+        return Err(Error::PositionNotFound(s.into()));
+    }
+
+    // Close a long by selling into the bids and a short by buying from the
+    // asks, priced at the level that covers the whole position.
+    let is_long = pos_size > 0;
+    let size = div_to_float(pos_size, decimals).abs();
+    let (slab, side) = match is_long {
+        true => (st.slab(mkt.bids).await?, zo::dex::Side::Bid),
+        false => (st.slab(mkt.asks).await?, zo::dex::Side::Ask),
+    };
+    let levels = book_side(&mkt, &slab, side);
+    let mut left = size;
+    let price = levels
+        .iter()
+        .find(|o| {
+            left -= o.size;
+            left <= 0.
+        })
+        .or_else(|| levels.last())
+        .ok_or_else(|| Error::InsufficientLiquidity(s.to_string()))?
+        .price;
+
+    // Fills of the closing order are the ones pushed to the event queue
+    // after this, as the taker's side of each trade is ours.
+    let next_seq = event_queue(&st, mkt.event_q)
+        .await?
+        .map(|(seqs, _)| seqs.end);
+    let (limit_price, max_base_quantity, max_quote_quantity) =
+        order_lots(&mkt, price, size)?;
+    let sent = st
+        .with_rpc(move |st| {
+            place_order(
+                &st,
+                &mkt,
+                margin.control,
+                open_orders,
+                zo::instruction::PlacePerpOrder {
+                    is_long: !is_long,
+                    limit_price,
+                    max_base_quantity,
+                    max_quote_quantity,
+                    order_type: zo::OrderType::ReduceOnlyIoc,
+                    limit: st.default_order_limit,
+                    client_id: 0,
+                },
+                SendOptions::default(),
+            )
+        })
+        .await?;
+
+    // The order is immediate-or-cancel, so once the transaction is
+    // confirmed the position has changed by what it filled.
+    let st = st.with_read_commitment(Some(CommitmentLevel::Processed));
+    let after = match sent.confirmed {
+        true => st
+            .trader_accounts()
+            .await
+            .ok()
+            .map(|(_, control)| control.open_orders_agg[i].pos_size),
+        false => None,
+    };
+    let closed =
+        after.map(|x| pos_size.unsigned_abs().saturating_sub(x.unsigned_abs()));
+    // Its fills are all in the event queue too, unless the crank consumed
+    // some of them already, which makes the price unknown.
+    let fills = match (closed, next_seq) {
+        (Some(_), Some(from)) => event_queue(&st, mkt.event_q)
+            .await
+            .ok()
+            .flatten()
+            .filter(|(seqs, _)| seqs.start <= from)
+            .map(|(_, fills)| {
+                fills
+                    .into_iter()
+                    .filter(|f| f.seq >= from && f.control == margin.control)
+                    .fold((0, 0), |(b, q), f| (b + f.base, q + f.quote))
+            }),
+        _ => None,
+    };
+    let price = fills.filter(|&(base, _)| base > 0).map(|(base, quote)| {
+        div_to_float(quote, 6u32) / div_to_float(base, decimals)
+    });
+    Ok(sent_response(
+        HttpResponse::Ok(),
+        sent.confirmed,
+        PositionCloseResp {
+            sig: sent.sig.to_string(),
+            confirmed: sent.confirmed,
+            closed: closed.map(|x| div_to_float(x, decimals)),
+            remaining: after.map(|x| div_to_float(x, decimals).abs()),
+            price,
+        },
+    ))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct OrdersPageQuery {
+    /// Only return orders on this side of the book.
+    side: Option<Side>,
+    /// Number of orders to skip.
+    offset: Option<usize>,
+    /// Maximum number of orders to return.
+    count: Option<usize>,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct OrdersPage {
+    /// Number of orders before paging.
+    total: usize,
+    orders: Vec<Order>,
+    /// Lot sizes of the market, to convert amounts in lots back. Only
+    /// with `units=lots`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    coin_lot_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pc_lot_size: Option<u64>,
+}
+
+#[utoipa::path(
+    params(
+        ("symbol" = String, Path),
+        OrdersPageQuery,
+        UnitsQuery,
+        ReadQuery
+    ),
+    responses((status = 200, body = OrdersPage))
+)]
 #[get("/orders/{symbol}")]
 async fn orders(
     st: Data<State>,
-    s: Path<String>,
-) -> Result<Json<Vec<Order>>, Error> {
+    s: Symbol,
+    q: Query<OrdersPageQuery>,
+    u: Query<UnitsQuery>,
+    c: Query<ReadQuery>,
+) -> Result<Json<OrdersPage>, Error> {
+    let st = st.with_read_commitment(c.commitment);
+    let Book {
+        bids,
+        asks,
+        coin_lot_size,
+        pc_lot_size,
+    } = book(&st, &s, u.units).await?;
+    let all: Vec<_> = match q.side {
+        Some(Side::Bid) => bids,
+        Some(Side::Ask) => asks,
+        None => bids.into_iter().chain(asks).collect(),
+    };
+    let total = all.len();
+    let orders = all
+        .into_iter()
+        .skip(q.offset.unwrap_or(0))
+        .take(q.count.unwrap_or(usize::MAX))
+        .collect();
+    Ok(Json(OrdersPage {
+        total,
+        orders,
+        coin_lot_size,
+        pc_lot_size,
+    }))
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct OpenInterest {
+    open_interest: f64,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct MarketDetail {
+    symbol: String,
+    oracle_symbol: String,
+    coin_lot_size: u64,
+    pc_lot_size: u64,
+    /// Smallest price increment, in USD.
+    tick_size: f64,
+    /// Smallest order size, in base units.
+    min_order_size: f64,
+    mark_price: f64,
+    index_price: Option<f64>,
+    /// Hourly funding rate implied by the current premium of the mark price
+    /// over the index price.
+    funding_rate: Option<f64>,
+    open_interest: f64,
+    initial_margin_fraction: f64,
+    /// Null if the market has no initial margin requirement.
+    max_leverage: Option<f64>,
+}
+
+#[utoipa::path(
+    params(("symbol" = String, Path), ReadQuery),
+    responses((status = 200, body = MarketDetail))
+)]
+#[get("/markets/{symbol}")]
+async fn market_detail(
+    st: Data<State>,
+    s: Symbol,
+    c: Query<ReadQuery>,
+) -> Result<Json<MarketDetail>, Error> {
+    let st = st.with_read_commitment(c.commitment);
+    let i = st.market_symbol_index(&s)?;
+    let info = st.market(&s)?;
+    let (decimals, oracle_symbol, imf, max_leverage) = (
+        info.asset_decimals,
+        String::from(info.oracle_symbol),
+        info.base_imf,
+        max_leverage(info),
+    );
+    let (cache, mkt) = tokio::try_join!(st.zo_cache(), st.dex_market(&s))?;
+    let mark = price_to_big(cache.marks[i].price.into(), decimals);
+    let index =
+        oracle_price(&cache, &oracle_symbol).map(|p| price_to_big(p, decimals));
+    let (coin_lot_size, pc_lot_size) = (mkt.coin_lot_size, mkt.pc_lot_size);
+    Ok(Json(MarketDetail {
+        symbol: s.into(),
+        oracle_symbol,
+        coin_lot_size,
+        pc_lot_size,
+        tick_size: price_to_big(
+            I80F48::from_num(pc_lot_size) / I80F48::from_num(coin_lot_size),
+            decimals,
+        ),
+        min_order_size: div_to_float(coin_lot_size, decimals),
+        mark_price: mark,
+        index_price: index,
+        // Funding is paid hourly on the daily premium.
+        funding_rate: index.filter(|&x| x > 0.).map(|x| (mark - x) / x / 24.),
+        open_interest: open_interest(&mkt, decimals),
+        initial_margin_fraction: imf as f64 / 1000.,
+        max_leverage,
+    }))
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct MarketStats {
+    mark_price: f64,
+    open_interest: f64,
+    /// Highest sampled mark price in the window.
+    high: Option<f64>,
+    /// Lowest sampled mark price in the window.
+    low: Option<f64>,
+    /// Change of the mark price since the start of the window.
+    price_change: Option<f64>,
+    price_change_percent: Option<f64>,
+    /// Traded volume isn't tracked on-chain, so this is always null.
+    volume: Option<f64>,
+    /// Seconds of history covered by `high`, `low` and the price change,
+    /// which is less than a day shortly after startup.
+    window_secs: u64,
+}
+
+#[utoipa::path(
+    params(("symbol" = String, Path), ReadQuery),
+    responses((status = 200, body = MarketStats))
+)]
+#[get("/markets/{symbol}/stats")]
+async fn market_stats(
+    st: Data<State>,
+    s: Symbol,
+    c: Query<ReadQuery>,
+) -> Result<Json<MarketStats>, Error> {
+    let st = st.with_read_commitment(c.commitment);
+    let i = st.market_symbol_index(&s)?;
+    let decimals = st.market(&s)?.asset_decimals;
+    let (cache, mkt) = tokio::try_join!(st.zo_cache(), st.dex_market(&s))?;
+    let mark = price_to_big(cache.marks[i].price.into(), decimals);
+    let summary = st.mark_history().summary(&s);
+    let open = summary.as_ref().map(|x| price_to_big(x.open, decimals));
+    Ok(Json(MarketStats {
+        mark_price: mark,
+        open_interest: open_interest(&mkt, decimals),
+        high: summary.as_ref().map(|x| price_to_big(x.high, decimals)),
+        low: summary.as_ref().map(|x| price_to_big(x.low, decimals)),
+        price_change: open.map(|x| mark - x),
+        price_change_percent: open
+            .filter(|&x| x > 0.)
+            .map(|x| (mark - x) / x * 100.),
+        volume: None,
+        window_secs: summary.map_or(0, |x| x.span.as_secs()),
+    }))
+}
+
+#[utoipa::path(
+    params(("symbol" = String, Path), ReadQuery),
+    responses((status = 200, body = OpenInterest))
+)]
+#[get("/markets/{symbol}/oi")]
+async fn market_open_interest(
+    st: Data<State>,
+    s: Symbol,
+    c: Query<ReadQuery>,
+) -> Result<Json<OpenInterest>, Error> {
+    let st = st.with_read_commitment(c.commitment);
+    let decimals = st.market(&s)?.asset_decimals;
     let mkt = st.dex_market(&s).await?;
-    let (bids, asks) = tokio::try_join!(st.slab(mkt.bids), st.slab(mkt.asks))?;
-    Ok(Json(
-        bids.iter_front()
-            .map(|o| mkt.parse_order(&o, zo::dex::Side::Bid))
-            .chain(
-                asks.iter_front()
-                    .map(|o| mkt.parse_order(o, zo::dex::Side::Ask)),
+    Ok(Json(OpenInterest {
+        open_interest: open_interest(&mkt, decimals),
+    }))
+}
+
+#[utoipa::path(
+    params(("symbol" = String, Path)),
+    responses(
+        (status = 200, body = SigResp),
+        (status = 202, body = SigResp, description = "Unconfirmed")
+    )
+)]
+#[post("/markets/{symbol}/settle")]
+async fn market_settle(
+    st: Data<State>,
+    s: Symbol,
+) -> Result<HttpResponse, Error> {
+    let mkt = st.dex_market(&s).await?;
+    let margin = st.zo_margin().await?;
+    let open_orders = st.oo(&s).await?;
+    let sent = st
+        .with_rpc(move |st| {
+            st.send(
+                st.program()
+                    .request()
+                    .args(zo::instruction::SettleFunds {})
+                    .accounts(zo::accounts::SettleFunds {
+                        authority: st.authority(),
+                        state: zo::ZO_STATE_ID,
+                        state_signer: st.zo_state_signer,
+                        cache: st.zo_state().cache,
+                        margin: st.zo_margin_key,
+                        control: margin.control,
+                        open_orders,
+                        dex_market: mkt.own_address,
+                        dex_program: zo::ZO_DEX_PID,
+                    }),
+                SendOptions::default(),
             )
-            .map(Into::into)
-            .collect::<Vec<_>>(),
+        })
+        .await?;
+    Ok(sent_response(
+        HttpResponse::Ok(),
+        sent.confirmed,
+        SigResp::from(sent),
     ))
 }
 
-#[derive(Deserialize)]
+fn place_order_accounts(
+    st: &State,
+    mkt: &zo::dex::ZoDexMarket,
+    control: Pubkey,
+    open_orders: Pubkey,
+) -> zo::accounts::PlacePerpOrder {
+    zo::accounts::PlacePerpOrder {
+        state: zo::ZO_STATE_ID,
+        state_signer: st.zo_state_signer,
+        cache: st.zo_state().cache,
+        authority: st.authority(),
+        margin: st.zo_margin_key,
+        control,
+        open_orders,
+        dex_market: mkt.own_address,
+        req_q: mkt.req_q,
+        event_q: mkt.event_q,
+        market_bids: mkt.bids,
+        market_asks: mkt.asks,
+        dex_program: zo::ZO_DEX_PID,
+        rent: rent::ID,
+    }
+}
+
+fn place_order(
+    st: &State,
+    mkt: &zo::dex::ZoDexMarket,
+    control: Pubkey,
+    open_orders: Pubkey,
+    args: zo::instruction::PlacePerpOrder,
+    opts: SendOptions,
+) -> Result<Sent, Error> {
+    st.send(
+        st.program()
+            .request()
+            .args(args)
+            .accounts(place_order_accounts(st, mkt, control, open_orders)),
+        opts,
+    )
+}
+
+/// Appends the instructions of `other` to `req`, for sending several
+/// instructions in one transaction.
+fn with_instructions<'a>(
+    mut req: RequestBuilder<'a>,
+    other: RequestBuilder,
+) -> Result<RequestBuilder<'a>, Error> {
+    for ix in other.instructions()? {
+        req = req.instruction(ix);
+    }
+    Ok(req)
+}
+
+/// Orders on one side of the book, best price first.
+fn book_side(
+    mkt: &zo::dex::ZoDexMarket,
+    slab: &zo::dex::Slab,
+    side: zo::dex::Side,
+) -> Vec<zo::dex::Order> {
+    let mut v: Vec<_> = slab
+        .iter_front()
+        .map(|o| mkt.parse_order(o, side))
+        .collect();
+    match side {
+        zo::dex::Side::Bid => v.sort_by(|a, b| b.price.total_cmp(&a.price)),
+        zo::dex::Side::Ask => v.sort_by(|a, b| a.price.total_cmp(&b.price)),
+    }
+    v
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct VwapQuery {
+    size: f64,
+    side: Side,
+}
+
+#[derive(Serialize, ToSchema)]
 #[serde(rename_all = "camelCase")]
-struct OrdersPostQuery {
+struct VwapResp {
+    vwap: Option<f64>,
+    filled: f64,
+    unfilled: f64,
+}
+
+#[utoipa::path(
+    params(("symbol" = String, Path), VwapQuery, ReadQuery),
+    responses((status = 200, body = VwapResp))
+)]
+#[get("/orderbook/{symbol}/vwap")]
+async fn orderbook_vwap(
+    st: Data<State>,
+    s: Symbol,
+    q: Query<VwapQuery>,
+    c: Query<ReadQuery>,
+) -> Result<Json<VwapResp>, Error> {
+    positive("size", q.size)?;
+    let st = st.with_read_commitment(c.commitment);
+    let mkt = st.dex_market(&s).await?;
+    // A buy fills against the asks and a sell against the bids.
+    let (slab, side) = match q.side {
+        Side::Bid => (st.slab(mkt.asks).await?, zo::dex::Side::Ask),
+        Side::Ask => (st.slab(mkt.bids).await?, zo::dex::Side::Bid),
+    };
+    let (mut filled, mut cost) = (0., 0.);
+    for o in book_side(&mkt, &slab, side) {
+        let size = o.size.min(q.size - filled);
+        filled += size;
+        cost += size * o.price;
+        if filled >= q.size {
+            break;
+        }
+    }
+    Ok(Json(VwapResp {
+        vwap: (filled > 0.).then(|| cost / filled),
+        filled,
+        unfilled: (q.size - filled).max(0.),
+    }))
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct NewOrder {
     size: f64,
     price: f64,
     side: Side,
@@ -310,102 +1898,904 @@ struct OrdersPostQuery {
     limit: Option<u16>,
 }
 
+impl NewOrder {
+    fn validate(&self) -> Result<(), Error> {
+        positive("size", self.size)?;
+        positive("price", self.price)?;
+        Ok(())
+    }
+
+    fn args(
+        &self,
+        mkt: &zo::dex::ZoDexMarket,
+        client_id: u64,
+        default_limit: u16,
+    ) -> Result<zo::instruction::PlacePerpOrder, Error> {
+        let (limit_price, max_base_quantity, max_quote_quantity) =
+            order_lots(mkt, self.price, self.size)?;
+        Ok(zo::instruction::PlacePerpOrder {
+            is_long: self.side == Side::Bid,
+            limit_price,
+            max_base_quantity,
+            max_quote_quantity,
+            order_type: self.order_type.into(),
+            limit: self.limit.unwrap_or(default_limit),
+            client_id,
+        })
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+struct OrdersPostQuery {
+    #[serde(flatten)]
+    order: NewOrder,
+    #[serde(flatten)]
+    send: SendOptions,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct OrdersPostResp {
+    #[serde(flatten)]
+    sent: SigResp,
+    client_id: u64,
+    /// For confirmed post-only orders, whether the order is resting on the
+    /// book. Left out if the book couldn't be read.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    posted: Option<bool>,
+    /// Why a post-only order didn't post.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+#[utoipa::path(
+    params(("symbol" = String, Path)),
+    request_body = OrdersPostQuery,
+    responses(
+        (status = 201, body = OrdersPostResp),
+        (status = 202, body = OrdersPostResp, description = "Unconfirmed")
+    )
+)]
 #[post("/orders/{symbol}")]
 async fn orders_post(
     st: Data<State>,
-    s: Path<String>,
+    s: Symbol,
     q: Json<OrdersPostQuery>,
 ) -> Result<HttpResponse, Error> {
+    q.order.validate()?;
     let mkt = st.dex_market(&s).await?;
     let margin = st.zo_margin().await?;
     let open_orders = st.oo(&s).await?;
-    let st = st.clone();
-    let sig = tokio::task::spawn_blocking(move || {
-        let limit_price = mkt.price_to_lots(q.price);
-        let max_base_quantity = mkt.size_to_lots(q.size);
-        let max_quote_quantity =
-            limit_price * max_base_quantity * mkt.pc_lot_size;
-        st.program()
-            .request()
-            .args(zo::instruction::PlacePerpOrder {
-                is_long: q.side == Side::Bid,
-                limit_price,
-                max_base_quantity,
-                max_quote_quantity,
-                order_type: q.order_type.into(),
-                limit: q.limit.unwrap_or(20),
-                client_id: q.client_id.unwrap_or(0),
-            })
-            .accounts(zo::accounts::PlacePerpOrder {
-                state: zo::ZO_STATE_ID,
-                state_signer: st.zo_state_signer,
-                cache: st.zo_state().cache,
-                authority: st.authority(),
-                margin: st.zo_margin_key,
-                control: margin.control,
-                open_orders,
-                dex_market: mkt.own_address,
-                req_q: mkt.req_q,
-                event_q: mkt.event_q,
-                market_bids: mkt.bids,
-                market_asks: mkt.asks,
-                dex_program: zo::ZO_DEX_PID,
-                rent: rent::ID,
-            })
-            .send()
-    })
-    .await
-    .unwrap()?
-    .to_string();
-    Ok(HttpResponse::Created().json(SigResp { sig }))
+    let client_id = st.reserve_client_id(q.order.client_id)?;
+    let id = client_id.id;
+    let (order_type, side) = (q.order.order_type, q.order.side);
+    let (slab, dex_side) = match side {
+        Side::Bid => (mkt.bids, zo::dex::Side::Bid),
+        Side::Ask => (mkt.asks, zo::dex::Side::Ask),
+    };
+    let resting = |slab: &zo::dex::Slab| -> HashSet<u128> {
+        book_side(&mkt, slab, dex_side)
+            .into_iter()
+            .filter(|o| o.control == margin.control && o.client_order_id == id)
+            .map(|o| o.order_id)
+            .collect()
+    };
+    // Orders that already rest with the same client id mustn't be taken
+    // for this one when checking whether it posted.
+    let before = match order_type {
+        OrderType::PostOnly => resting(&st.slab(slab).await?),
+        _ => HashSet::new(),
+    };
+    let args = q.order.args(&mkt, id, st.default_order_limit)?;
+    let sent = st
+        .with_rpc(move |st| {
+            place_order(&st, &mkt, margin.control, open_orders, args, q.send)
+        })
+        .await?;
+    drop(client_id);
+
+    // A post-only order that would cross is dropped without failing the
+    // transaction, so whether it posted can only be told from the book.
+    // That's read at the latest state, which has the transaction whatever
+    // commitment it was confirmed at. The order was placed either way, so
+    // failing to read the book only leaves `posted` unknown.
+    let posted = match (order_type, sent.confirmed) {
+        (OrderType::PostOnly, true) => st
+            .with_read_commitment(Some(CommitmentLevel::Processed))
+            .slab(slab)
+            .await
+            .ok()
+            .map(|slab| !resting(&slab).is_subset(&before)),
+        _ => None,
+    };
+    // The program logs why it dropped the order, but the logs are only
+    // looked at, not relied on, as they may not be served.
+    let reason = match posted {
+        Some(false) => {
+            let sig = sent.sig;
+            let logs = st.with_rpc(move |st| st.transaction_logs(&sig)).await;
+            Some(
+                logs.ok()
+                    .and_then(|logs| {
+                        logs.into_iter()
+                            .rev()
+                            .find(|l| l.to_lowercase().contains("post"))
+                    })
+                    .unwrap_or_else(|| "Would have crossed the book".into()),
+            )
+        }
+        _ => None,
+    };
+    Ok(sent_response(
+        HttpResponse::Created(),
+        sent.confirmed,
+        OrdersPostResp {
+            sent: sent.into(),
+            client_id: id,
+            posted,
+            reason,
+        },
+    ))
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct ReplaceLadderQuery {
+    /// Cancel all of our resting orders in the market.
+    #[serde(default)]
+    cancel_all: bool,
+    /// Ids of orders to cancel, decimal or `0x`-prefixed hex.
+    #[serde(default)]
+    cancel: Vec<String>,
+    orders: Vec<NewOrder>,
+    /// Fail instead of splitting the request across transactions when it
+    /// doesn't fit in one. Defaults to true.
+    atomic: Option<bool>,
+    #[serde(flatten)]
+    send: SendOptions,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct ReplaceLadderResp {
+    /// One signature per transaction, in the order they were sent.
+    sigs: Vec<String>,
+    confirmed: bool,
+    cancelled: usize,
+    /// Client ids of the placed orders, in the order they were given.
+    client_ids: Vec<u64>,
 }
 
-#[derive(Deserialize)]
+#[utoipa::path(
+    params(("symbol" = String, Path)),
+    request_body = ReplaceLadderQuery,
+    responses(
+        (status = 201, body = ReplaceLadderResp),
+        (status = 202, body = ReplaceLadderResp, description = "Unconfirmed"),
+        (status = 204, description = "Nothing to cancel or place")
+    )
+)]
+#[post("/orders/{symbol}/replace-ladder")]
+async fn orders_replace_ladder(
+    st: Data<State>,
+    s: Symbol,
+    q: Json<ReplaceLadderQuery>,
+) -> Result<HttpResponse, Error> {
+    let q = q.into_inner();
+    for o in &q.orders {
+        o.validate()?;
+    }
+    let mkt = st.dex_market(&s).await?;
+    let margin = st.zo_margin().await?;
+    let open_orders = st.oo(&s).await?;
+    let (bids, asks) = tokio::try_join!(st.slab(mkt.bids), st.slab(mkt.asks))?;
+    let resting: HashMap<u128, Side> = bids
+        .iter_front()
+        .map(|o| mkt.parse_order(o, zo::dex::Side::Bid))
+        .chain(
+            asks.iter_front()
+                .map(|o| mkt.parse_order(o, zo::dex::Side::Ask)),
+        )
+        .filter(|o| o.control == margin.control)
+        .map(|o| (o.order_id, o.side.into()))
+        .collect();
+
+    // Orders that aren't resting anymore are skipped, as cancelling them
+    // would fail the whole transaction.
+    let cancels: Vec<(u128, Side)> = match q.cancel_all {
+        true => resting.into_iter().collect(),
+        false => q
+            .cancel
+            .iter()
+            .map(|s| parse_order_id(s, None))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .filter_map(|id| resting.get(&id).map(|&side| (id, side)))
+            .collect(),
+    };
+    if cancels.is_empty() && q.orders.is_empty() {
+        return Ok(HttpResponse::NoContent().finish());
+    }
+
+    let client_ids = q
+        .orders
+        .iter()
+        .map(|o| st.reserve_client_id(o.client_id))
+        .collect::<Result<Vec<_>, _>>()?;
+    let ids: Vec<u64> = client_ids.iter().map(|x| x.id).collect();
+    let places = q
+        .orders
+        .iter()
+        .zip(&ids)
+        .map(|(o, &id)| o.args(&mkt, id, st.default_order_limit))
+        .collect::<Result<Vec<_>, _>>()?;
+    let cancelled = cancels.len();
+    let sent = st
+        .with_rpc(move |st| {
+            let control = margin.control;
+            let mut req = st.program().request();
+            for (order_id, side) in cancels {
+                req = with_instructions(
+                    req,
+                    st.program()
+                        .request()
+                        .args(zo::instruction::CancelPerpOrder {
+                            order_id: Some(order_id),
+                            is_long: Some(side == Side::Bid),
+                            client_id: None,
+                        })
+                        .accounts(cancel_accounts(
+                            &st,
+                            &mkt,
+                            control,
+                            open_orders,
+                        )),
+                )?;
+            }
+            for args in places {
+                req = with_instructions(
+                    req,
+                    st.program().request().args(args).accounts(
+                        place_order_accounts(&st, &mkt, control, open_orders),
+                    ),
+                )?;
+            }
+            let atomic = q.atomic.unwrap_or(true);
+            st.send_all(req.instructions()?, atomic, q.send)
+        })
+        .await?;
+    drop(client_ids);
+    let confirmed = sent.iter().all(|x| x.confirmed);
+    Ok(sent_response(
+        HttpResponse::Created(),
+        confirmed,
+        ReplaceLadderResp {
+            sigs: sent.iter().map(|x| x.sig.to_string()).collect(),
+            confirmed,
+            cancelled,
+            client_ids: ids,
+        },
+    ))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 struct OrdersDeleteQuery {
+    /// Decimal, or hexadecimal if prefixed with `0x` or if `base` is 16.
     order_id: Option<String>,
+    base: Option<OrderIdBase>,
     side: Option<Side>,
     client_id: Option<u64>,
+    // The send options are spelled out, rather than flattened from
+    // `SendOptions`, since flattening loses the types of query values. The
+    // aliases accept the names used in the body of other requests.
+    #[serde(default, alias = "skipPreflight")]
+    skip_preflight: bool,
+    #[serde(alias = "preflightCommitment")]
+    #[param(value_type = Option<String>)]
+    preflight_commitment: Option<CommitmentLevel>,
+    #[serde(default, alias = "returnComputeUnits")]
+    return_compute_units: bool,
+    confirm: Option<bool>,
+    #[serde(alias = "confirmTimeout")]
+    confirm_timeout: Option<u64>,
+    #[serde(alias = "confirmCommitment")]
+    #[param(value_type = Option<String>)]
+    confirm_commitment: Option<CommitmentLevel>,
 }
 
+impl From<&OrdersDeleteQuery> for SendOptions {
+    fn from(q: &OrdersDeleteQuery) -> Self {
+        Self {
+            skip_preflight: q.skip_preflight,
+            preflight_commitment: q.preflight_commitment,
+            return_compute_units: q.return_compute_units,
+            confirm: q.confirm,
+            confirm_timeout: q.confirm_timeout,
+            confirm_commitment: q.confirm_commitment,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct CancelResp {
+    #[serde(flatten)]
+    sent: SigResp,
+    /// Size that was still resting, and so is freed by the cancel.
+    freed_base: f64,
+    /// Value of the freed size at the order's price.
+    freed_quote: f64,
+}
+
+#[derive(Deserialize, ToSchema, Clone, Copy)]
+enum OrderIdBase {
+    #[serde(rename = "10")]
+    Dec,
+    #[serde(rename = "16")]
+    Hex,
+}
+
+fn parse_order_id(s: &str, base: Option<OrderIdBase>) -> Result<u128, Error> {
+    let hex = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X"));
+    let (s, radix) = match (hex, base) {
+        (Some(_), Some(OrderIdBase::Dec)) => {
+            return Err(Error::InvalidInput(format!(
+                "Order id {} has a hex prefix, but base 10 was given",
+                s
+            )))
+        }
+        (Some(s), _) => (s, 16),
+        (None, Some(OrderIdBase::Hex)) => (s, 16),
+        (None, _) => (s, 10),
+    };
+    Ok(u128::from_str_radix(s, radix)?)
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct CancelSideResp {
+    cancelled: usize,
+    sigs: Vec<String>,
+    confirmed: bool,
+    /// Total over all the transactions, if asked for and found for each.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    compute_units: Option<u64>,
+}
+
+fn cancel_accounts(
+    st: &State,
+    mkt: &zo::dex::ZoDexMarket,
+    control: Pubkey,
+    open_orders: Pubkey,
+) -> zo::accounts::CancelPerpOrder {
+    zo::accounts::CancelPerpOrder {
+        state: zo::ZO_STATE_ID,
+        cache: st.zo_state().cache,
+        authority: st.authority(),
+        margin: st.zo_margin_key,
+        control,
+        open_orders,
+        dex_market: mkt.own_address,
+        event_q: mkt.event_q,
+        market_bids: mkt.bids,
+        market_asks: mkt.asks,
+        dex_program: zo::ZO_DEX_PID,
+    }
+}
+
+/// Cancels all of our resting orders on one side of the book.
+async fn cancel_side(
+    st: &State,
+    mkt: zo::dex::ZoDexMarket,
+    control: Pubkey,
+    open_orders: Pubkey,
+    side: Side,
+    opts: SendOptions,
+) -> Result<HttpResponse, Error> {
+    let (slab, dex_side) = match side {
+        Side::Bid => (mkt.bids, zo::dex::Side::Bid),
+        Side::Ask => (mkt.asks, zo::dex::Side::Ask),
+    };
+    let slab = st.slab(slab).await?;
+    let ids: Vec<u128> = book_side(&mkt, &slab, dex_side)
+        .into_iter()
+        .filter(|o| o.control == control)
+        .map(|o| o.order_id)
+        .collect();
+    if ids.is_empty() {
+        return Ok(HttpResponse::NoContent().finish());
+    }
+
+    let cancelled = ids.len();
+    let sent = st
+        .with_rpc(move |st| {
+            let mut req = st.program().request();
+            for order_id in ids {
+                req = with_instructions(
+                    req,
+                    st.program()
+                        .request()
+                        .args(zo::instruction::CancelPerpOrder {
+                            order_id: Some(order_id),
+                            is_long: Some(side == Side::Bid),
+                            client_id: None,
+                        })
+                        .accounts(cancel_accounts(
+                            &st,
+                            &mkt,
+                            control,
+                            open_orders,
+                        )),
+                )?;
+            }
+            st.send_all(req.instructions()?, false, opts)
+        })
+        .await?;
+    let confirmed = sent.iter().all(|x| x.confirmed);
+    Ok(sent_response(
+        HttpResponse::Ok(),
+        confirmed,
+        CancelSideResp {
+            cancelled,
+            sigs: sent.iter().map(|x| x.sig.to_string()).collect(),
+            confirmed,
+            compute_units: sent.iter().map(|x| x.compute_units).sum(),
+        },
+    ))
+}
+
+#[utoipa::path(
+    params(("symbol" = String, Path), OrdersDeleteQuery),
+    responses(
+        (status = 200, body = CancelResp),
+        (status = 202, description = "Cancel sent but not confirmed yet"),
+        (status = 204, description = "Nothing to cancel"),
+        (status = 404, description = "No open orders account")
+    )
+)]
 #[delete("/orders/{symbol}")]
 async fn orders_delete(
     st: Data<State>,
-    s: Path<String>,
+    s: Symbol,
     q: Query<OrdersDeleteQuery>,
 ) -> Result<HttpResponse, Error> {
     let order_id = match q.order_id {
-        Some(ref s) => Some(u128::from_str_radix(s, 10)?),
+        Some(ref s) => Some(parse_order_id(s, q.base)?),
         None => None,
     };
+    let open_orders = match st.oo_info(&s).await {
+        Ok(oo) if oo.order_count == 0 => {
+            return Ok(HttpResponse::NoContent().finish())
+        }
+        Ok(oo) => oo.key,
+        Err(Error::OpenOrdersNotFound(s)) => {
+            return Err(Error::NothingToCancel(s))
+        }
+        Err(e) => return Err(e),
+    };
     let mkt = st.dex_market(&s).await?;
     let margin = st.zo_margin().await?;
-    let open_orders = st.oo(&s).await?;
-    let st = st.clone();
-    let sig = tokio::task::spawn_blocking(move || {
-        st.program()
-            .request()
-            .args(zo::instruction::CancelPerpOrder {
-                order_id: order_id,
-                is_long: q.side.map(|s| s == Side::Bid),
-                client_id: q.client_id,
-            })
-            .accounts(zo::accounts::CancelPerpOrder {
-                state: zo::ZO_STATE_ID,
-                cache: st.zo_state().cache,
-                authority: st.authority(),
-                margin: st.zo_margin_key,
-                control: margin.control,
-                open_orders,
-                dex_market: mkt.own_address,
-                event_q: mkt.event_q,
-                market_bids: mkt.bids,
-                market_asks: mkt.asks,
-                dex_program: zo::ZO_DEX_PID,
-            })
-            .send()
-    })
-    .await
-    .unwrap()?
-    .to_string();
-    Ok(HttpResponse::NoContent().json(SigResp { sig }))
+    let opts = SendOptions::from(&*q);
+    if let (None, None, Some(side)) = (order_id, q.client_id, q.side) {
+        return cancel_side(&st, mkt, margin.control, open_orders, side, opts)
+            .await;
+    }
+
+    // What's left of the order is read from the book beforehand, as it's
+    // gone from it once cancelled.
+    let (bids, asks) = tokio::try_join!(st.slab(mkt.bids), st.slab(mkt.asks))?;
+    let (freed_base, freed_quote) = bids
+        .iter_front()
+        .map(|o| mkt.parse_order(o, zo::dex::Side::Bid))
+        .chain(
+            asks.iter_front()
+                .map(|o| mkt.parse_order(o, zo::dex::Side::Ask)),
+        )
+        .find(|o| {
+            o.control == margin.control
+                && q.side.map_or(true, |s| s == o.side.into())
+                && match order_id {
+                    Some(id) => o.order_id == id,
+                    None => Some(o.client_order_id) == q.client_id,
+                }
+        })
+        .map_or((0., 0.), |o| (o.size, o.size * o.price));
+
+    let sent = st
+        .with_rpc(move |st| {
+            st.send(
+                st.program()
+                    .request()
+                    .args(zo::instruction::CancelPerpOrder {
+                        order_id: order_id,
+                        is_long: q.side.map(|s| s == Side::Bid),
+                        client_id: q.client_id,
+                    })
+                    .accounts(cancel_accounts(
+                        &st,
+                        &mkt,
+                        margin.control,
+                        open_orders,
+                    )),
+                opts,
+            )
+        })
+        .await?;
+    Ok(sent_response(
+        HttpResponse::Ok(),
+        sent.confirmed,
+        CancelResp {
+            sent: sent.into(),
+            freed_base,
+            freed_quote,
+        },
+    ))
+}
+
+#[derive(Serialize, ToSchema)]
+struct OpenOrdersCloseResp {
+    sig: String,
+    confirmed: bool,
+    /// Rent returned to the authority, in lamports.
+    lamports: u64,
+}
+
+#[utoipa::path(
+    params(("symbol" = String, Path)),
+    responses(
+        (status = 200, body = OpenOrdersCloseResp),
+        (status = 202, body = OpenOrdersCloseResp, description = "Unconfirmed"),
+        (status = 404, description = "No open orders account"),
+        (status = 409, description = "Orders or a position remain")
+    )
+)]
+#[delete("/orders/{symbol}/account")]
+async fn orders_account_close(
+    st: Data<State>,
+    s: Symbol,
+) -> Result<HttpResponse, Error> {
+    let oo = st.oo_info(&s).await?;
+    let (order_count, pos_size) = (oo.order_count, oo.pos_size);
+    if order_count != 0 {
+        return Err(Error::OpenOrdersInUse(s.into(), "resting orders"));
+    }
+    if pos_size != 0 {
+        return Err(Error::OpenOrdersInUse(s.into(), "an open position"));
+    }
+    let open_orders = oo.key;
+    let mkt = st.dex_market(&s).await?;
+    let margin = st.zo_margin().await?;
+    let (sent, lamports) = st
+        .with_rpc(move |st| {
+            let lamports = st.account(&open_orders)?.map_or(0, |a| a.lamports);
+            // Anything left unsettled would be lost with the account, so
+            // it's settled first in the same transaction. Instructions
+            // given as args go last, so both are appended in order.
+            let req = with_instructions(
+                st.program().request(),
+                st.program()
+                    .request()
+                    .args(zo::instruction::SettleFunds {})
+                    .accounts(zo::accounts::SettleFunds {
+                        authority: st.authority(),
+                        state: zo::ZO_STATE_ID,
+                        state_signer: st.zo_state_signer,
+                        cache: st.zo_state().cache,
+                        margin: st.zo_margin_key,
+                        control: margin.control,
+                        open_orders,
+                        dex_market: mkt.own_address,
+                        dex_program: zo::ZO_DEX_PID,
+                    }),
+            )?;
+            let req = with_instructions(
+                req,
+                st.program()
+                    .request()
+                    .args(zo::instruction::ClosePerpOpenOrders {})
+                    .accounts(zo::accounts::ClosePerpOpenOrders {
+                        authority: st.authority(),
+                        state: zo::ZO_STATE_ID,
+                        state_signer: st.zo_state_signer,
+                        margin: st.zo_margin_key,
+                        control: margin.control,
+                        open_orders,
+                        dex_market: mkt.own_address,
+                        dex_program: zo::ZO_DEX_PID,
+                    }),
+            )?;
+            Ok((st.send(req, SendOptions::default())?, lamports))
+        })
+        .await?;
+    Ok(sent_response(
+        HttpResponse::Ok(),
+        sent.confirmed,
+        OpenOrdersCloseResp {
+            sig: sent.sig.to_string(),
+            confirmed: sent.confirmed,
+            lamports,
+        },
+    ))
+}
+
+#[derive(Serialize)]
+struct Print {
+    price: f64,
+    size: f64,
+    /// Side of the taker.
+    side: Side,
+    /// Unix timestamp in milliseconds of when the print was seen.
+    time: u128,
+}
+
+#[utoipa::path(
+    params(("symbol" = String, Path)),
+    responses((status = 101, description = "Switching to WebSocket"))
+)]
+#[get("/ws/trades/{symbol}")]
+async fn ws_trades(
+    st: Data<State>,
+    s: Symbol,
+    req: HttpRequest,
+    body: Payload,
+) -> Result<HttpResponse, actix_web::Error> {
+    let decimals = st.market(&s)?.asset_decimals;
+    let mkt = st.dex_market(&s).await?;
+    let (res, mut session, mut msgs) = actix_ws::handle(&req, body)?;
+    let mut updates = st.pubsub().subscribe(mkt.event_q);
+
+    actix_web::rt::spawn(async move {
+        let mut new_fills = NewFills::default();
+        loop {
+            tokio::select! {
+                update = updates.recv() => match update {
+                    Ok(a) => {
+                        let fills = match new_fills.read(&a.data) {
+                            Some(x) => x,
+                            None => continue,
+                        };
+                        let time = SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .unwrap()
+                            .as_millis();
+                        for f in fills {
+                            if f.base == 0 {
+                                continue;
+                            }
+                            let size = div_to_float(f.base, decimals);
+                            let print = Print {
+                                price: div_to_float(f.quote, 6u32) / size,
+                                size,
+                                side: match f.bid {
+                                    true => Side::Bid,
+                                    false => Side::Ask,
+                                },
+                                time,
+                            };
+                            let text = serde_json::to_string(&print).unwrap();
+                            if session.text(text).await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    // Skipped updates are covered by the next one, as long
+                    // as the events weren't consumed in between.
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                },
+                msg = msgs.next() => match msg {
+                    Some(Ok(Message::Ping(b))) => {
+                        if session.pong(&b).await.is_err() {
+                            return;
+                        }
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                        break
+                    }
+                    Some(Ok(_)) => {}
+                },
+            }
+        }
+        let _ = session.close(None).await;
+    });
+    Ok(res)
+}
+
+/// Describes the versioned routes. `/metrics` and this document itself are
+/// only served at the root, so they're left out.
+#[derive(OpenApi)]
+#[openapi(
+    servers((url = "/v1")),
+    paths(
+        time,
+        collateral_balances,
+        collateral_rates,
+        collateral_history,
+        collateral_deposit,
+        collateral_withdraw,
+        account,
+        position,
+        position_by_owner,
+        position_close,
+        market_open_interest,
+        market_detail,
+        market_stats,
+        orderbook_vwap,
+        ws_trades,
+        market_settle,
+        orders_multi,
+        orders_open,
+        orders,
+        orders_post,
+        orders_replace_ladder,
+        orders_delete,
+        orders_account_close,
+    ),
+    components(schemas(
+        Side,
+        OrderType,
+        Units,
+        Amount,
+        Order,
+        Book,
+        OrdersPage,
+        SigResp,
+        Time,
+        BalancesResp,
+        CollateralBalance,
+        CollateralEvent,
+        CollateralEventKind,
+        CollateralRates,
+        PositionInfo,
+        PositionsResp,
+        Health,
+        PositionCloseResp,
+        MarketLimits,
+        AccountInfo,
+        OpenInterest,
+        MarketDetail,
+        MarketStats,
+        VwapResp,
+        CollateralDepositQuery,
+        CollateralWithdrawQuery,
+        OrdersPostQuery,
+        OrdersPostResp,
+        NewOrder,
+        ReplaceLadderQuery,
+        ReplaceLadderResp,
+        OrderIdBase,
+        CancelResp,
+        CancelSideResp,
+        OpenOrdersCloseResp,
+        SendOptions,
+    ))
+)]
+struct ApiDoc;
+
+#[get("/openapi.json")]
+async fn openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+/// Registers the versioned API routes.
+pub fn routes(cfg: &mut ServiceConfig) {
+    cfg.service(time)
+        .service(collateral_balances)
+        .service(collateral_rates)
+        .service(collateral_history)
+        .service(collateral_deposit)
+        .service(collateral_withdraw)
+        .service(account)
+        .service(position)
+        .service(position_by_owner)
+        .service(position_close)
+        .service(market_open_interest)
+        .service(market_detail)
+        .service(market_stats)
+        .service(orderbook_vwap)
+        .service(market_settle)
+        .service(orders_multi)
+        // Before `orders`, which would take `open` for a symbol.
+        .service(orders_open)
+        .service(orders)
+        .service(orders_post)
+        .service(orders_replace_ladder)
+        .service(orders_delete)
+        .service(orders_account_close)
+        .service(ws_trades);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_order_ids() {
+        let id = 0x1e240;
+        assert_eq!(parse_order_id("123456", None).unwrap(), id);
+        assert_eq!(parse_order_id("0x1e240", None).unwrap(), id);
+        assert_eq!(parse_order_id("0X1E240", None).unwrap(), id);
+        assert_eq!(
+            parse_order_id("1e240", Some(OrderIdBase::Hex)).unwrap(),
+            id
+        );
+        assert_eq!(
+            parse_order_id("0x1e240", Some(OrderIdBase::Hex)).unwrap(),
+            id
+        );
+        assert_eq!(
+            parse_order_id("123456", Some(OrderIdBase::Dec)).unwrap(),
+            123456
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_order_ids() {
+        for (s, base) in [
+            ("", None),
+            ("0x", None),
+            ("1e240", None),
+            ("1e240", Some(OrderIdBase::Dec)),
+            ("0xg", None),
+            ("-1", None),
+            ("340282366920938463463374607431768211456", None),
+        ] {
+            assert!(
+                matches!(parse_order_id(s, base), Err(Error::ParseInt(_))),
+                "{:?}",
+                s
+            );
+        }
+        assert!(matches!(
+            parse_order_id("0x1e240", Some(OrderIdBase::Dec)),
+            Err(Error::InvalidInput(_))
+        ));
+    }
+
+    #[test]
+    fn reads_send_options_of_cancels_in_either_case() {
+        for s in [
+            "side=bid&skip_preflight=true&confirm_timeout=5",
+            "side=bid&skipPreflight=true&confirmTimeout=5",
+        ] {
+            let q = Query::<OrdersDeleteQuery>::from_query(s).unwrap();
+            let opts = SendOptions::from(&*q);
+            assert!(opts.skip_preflight, "{}", s);
+            assert_eq!(opts.confirm_timeout, Some(5), "{}", s);
+        }
+    }
+
+    #[test]
+    fn accepts_only_positive_numbers() {
+        assert_eq!(positive("size", 0.5).unwrap(), 0.5);
+        assert_eq!(
+            positive("size", f64::MIN_POSITIVE).unwrap(),
+            f64::MIN_POSITIVE
+        );
+        for x in [0., -0., -1., f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            assert!(
+                matches!(positive("size", x), Err(Error::InvalidInput(_))),
+                "{}",
+                x
+            );
+        }
+    }
+
+    #[test]
+    fn converts_amounts_to_smallest_units() {
+        assert_eq!(big_to_small("amount", 1.5, 6).unwrap(), 1_500_000);
+        assert_eq!(big_to_small("amount", 0.25, 2).unwrap(), 25);
+        assert_eq!(big_to_small("amount", 42., 0).unwrap(), 42);
+        for (n, decimals) in [(0.0000001, 6), (0.5, 0), (2e13, 6), (1e20, 0)] {
+            assert!(
+                matches!(
+                    big_to_small("amount", n, decimals),
+                    Err(Error::InvalidInput(_))
+                ),
+                "{}",
+                n
+            );
+        }
+    }
 }
@@ -0,0 +1,95 @@
+use anchor_client::solana_sdk::{
+    account::Account, commitment_config::CommitmentLevel, pubkey::Pubkey,
+};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Recent reads of the accounts that nearly every request needs, the
+/// trader's margin and control and the zo cache, kept for a short time so
+/// that clients polling the service don't each cost an RPC request.
+///
+/// Entries are per commitment, as reads can ask for a different one, and
+/// remember the slot they were read at so that reads which need several
+/// accounts from the same slot can tell whether the cached ones are.
+pub struct AccountCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<(Pubkey, CommitmentLevel), Entry>>,
+    /// Number of times the cache was cleared, so that reads which were in
+    /// flight during a clear don't put back what it dropped.
+    generation: AtomicU64,
+}
+
+struct Entry {
+    at: Instant,
+    slot: u64,
+    account: Account,
+}
+
+impl AccountCache {
+    /// A zero `ttl` disables the cache.
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::default(),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    /// The account at `k` and the slot it was read at, if it was read at
+    /// `c` less than the TTL ago.
+    pub fn get(
+        &self,
+        k: &Pubkey,
+        c: CommitmentLevel,
+    ) -> Option<(u64, Account)> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(&(*k, c))
+            .filter(|e| e.at.elapsed() < self.ttl)
+            .map(|e| (e.slot, e.account.clone()))
+    }
+
+    /// To be taken before reading an account that's then inserted.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Caches `account`, unless the cache was cleared since `generation`
+    /// was taken, as it may have been read before the change that caused
+    /// the clear.
+    pub fn insert(
+        &self,
+        generation: u64,
+        k: Pubkey,
+        c: CommitmentLevel,
+        slot: u64,
+        account: Account,
+    ) {
+        if self.ttl.is_zero() {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if self.generation() != generation {
+            return;
+        }
+        // Reads of other owners' accounts are cached too, so expired
+        // entries are dropped to keep the map from growing unbounded.
+        entries.retain(|_, e| e.at.elapsed() < self.ttl);
+        let at = Instant::now();
+        entries.insert((k, c), Entry { at, slot, account });
+    }
+
+    /// Drops every entry. A transaction may change any of the cached
+    /// accounts, so this is done after each one that's sent.
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.clear();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+}
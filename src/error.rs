@@ -1,11 +1,54 @@
+use actix_web::{
+    http::{header, StatusCode},
+    HttpResponse,
+};
+use anchor_client::solana_client::{
+    client_error::{ClientError as SolanaClientError, ClientErrorKind},
+    rpc_request::{RpcError, RpcResponseErrorData},
+};
+use serde::Serialize;
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
+    #[error("Invalid input: {0}")]
+    InvalidInput(String),
+    #[error("Invalid symbol {0:?}")]
+    InvalidSymbol(String),
     #[error("Could not find market {0}")]
     MarketSymbolNotFound(String),
     #[error("Could not find collateral {0}")]
     CollateralSymbolNotFound(String),
     #[error("Open orders account for {0} not created yet")]
     OpenOrdersNotFound(String),
+    #[error("Margin account {0} not found")]
+    MarginNotFound(anchor_client::solana_sdk::pubkey::Pubkey),
+    #[error("No open orders for {0}, nothing to cancel")]
+    NothingToCancel(String),
+    #[error("No open position for {0}")]
+    PositionNotFound(String),
+    #[error("Not enough liquidity in {0}")]
+    InsufficientLiquidity(String),
+    #[error("Transaction of {0} bytes is too large, try fewer orders")]
+    TransactionTooLarge(usize),
+    #[error("Request needs {0} transactions, but was asked to be atomic")]
+    NotAtomic(usize),
+    #[error("Sent {} transactions before one failed: {source}", sigs.len())]
+    PartiallySent {
+        sigs: Vec<anchor_client::solana_sdk::signature::Signature>,
+        source: Box<Error>,
+    },
+    #[error("Open orders account for {0} still has {1}")]
+    OpenOrdersInUse(String, &'static str),
+    #[error("An order with client id {0} is already being placed")]
+    DuplicateClientId(u64),
+    #[error("Invalid token account: {0}")]
+    InvalidTokenAccount(String),
+    #[error("No oracle price for {0}")]
+    PriceNotFound(String),
+    #[error("RPC node is behind by {slots} slots")]
+    NodeBehind { slots: u64 },
+    #[error("Signer failed: {0}")]
+    Signer(String),
     #[error("{0}")]
     Io(#[from] std::io::Error),
     #[error("{0}")]
@@ -20,4 +63,102 @@ pub enum Error {
     ParseInt(#[from] std::num::ParseIntError),
 }
 
-impl actix_web::ResponseError for Error {}
+impl Error {
+    fn solana_client(&self) -> Option<&SolanaClientError> {
+        match self {
+            Self::SolanaClient(e) => Some(e),
+            Self::AnchorClient(
+                anchor_client::ClientError::SolanaClientError(e),
+            ) => Some(e),
+            _ => None,
+        }
+    }
+
+    /// Whether the RPC node rejected the request for exceeding its rate
+    /// limit.
+    pub fn is_rate_limited(&self) -> bool {
+        if let Self::PartiallySent { source, .. } = self {
+            return source.is_rate_limited();
+        }
+        match self.solana_client().map(|e| e.kind()) {
+            Some(ClientErrorKind::Reqwest(e)) => {
+                e.status() == Some(reqwest::StatusCode::TOO_MANY_REQUESTS)
+            }
+            _ => false,
+        }
+    }
+
+    /// Replaces the RPC node's "node is behind" error with `NodeBehind`,
+    /// so that it's reported as a temporary condition.
+    pub fn detect_node_behind(self) -> Self {
+        match self.solana_client().map(|e| e.kind()) {
+            Some(ClientErrorKind::RpcError(RpcError::RpcResponseError {
+                data: RpcResponseErrorData::NodeUnhealthy { num_slots_behind },
+                ..
+            })) => Self::NodeBehind {
+                slots: num_slots_behind.unwrap_or(0),
+            },
+            _ => self,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorResp {
+    error: String,
+    /// Transactions that were sent before the error.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    sigs: Vec<String>,
+}
+
+impl actix_web::ResponseError for Error {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::MarketSymbolNotFound(_)
+            | Self::CollateralSymbolNotFound(_)
+            | Self::OpenOrdersNotFound(_)
+            | Self::MarginNotFound(_)
+            | Self::NothingToCancel(_)
+            | Self::PositionNotFound(_) => StatusCode::NOT_FOUND,
+            Self::InsufficientLiquidity(_)
+            | Self::OpenOrdersInUse(..)
+            | Self::DuplicateClientId(_) => StatusCode::CONFLICT,
+            Self::InvalidInput(_)
+            | Self::InvalidSymbol(_)
+            | Self::InvalidTokenAccount(_)
+            | Self::TransactionTooLarge(_)
+            | Self::NotAtomic(_)
+            | Self::ParsePubkey(_)
+            | Self::ParseInt(_) => StatusCode::BAD_REQUEST,
+            // The body also lists what was sent before it failed.
+            Self::PartiallySent { source, .. } => source.status_code(),
+            Self::PriceNotFound(_) | Self::NodeBehind { .. } => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut res = HttpResponse::build(self.status_code());
+        let cause = match self {
+            Self::PartiallySent { source, .. } => source.as_ref(),
+            _ => self,
+        };
+        if let Self::NodeBehind { slots } = cause {
+            // Roughly the time it takes to produce that many slots.
+            let secs = (slots * 2 / 5).clamp(1, 30);
+            res.insert_header((header::RETRY_AFTER, secs.to_string()));
+        }
+        let sigs = match self {
+            Self::PartiallySent { sigs, .. } => {
+                sigs.iter().map(|x| x.to_string()).collect()
+            }
+            _ => Vec::new(),
+        };
+        res.json(ErrorResp {
+            error: self.to_string(),
+            sigs,
+        })
+    }
+}
@@ -0,0 +1,228 @@
+use anchor_client::solana_sdk::pubkey::Pubkey;
+use bytemuck::{Pod, Zeroable};
+use std::{mem::size_of, ops::Range};
+
+const FILL: u8 = 1 << 0;
+const BID: u8 = 1 << 2;
+const MAKER: u8 = 1 << 3;
+
+/// Like serum's, dex accounts are padded with 5 bytes in front and 7 at
+/// the end.
+const HEAD_PADDING: usize = 5;
+const TAIL_PADDING: usize = 7;
+
+// The layouts mirror the program's, which zo-abi doesn't export, so not
+// every field is read.
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+struct Header {
+    _account_flags: u64,
+    head: u64,
+    count: u64,
+    /// Number of events ever pushed to the queue.
+    seq_num: u64,
+}
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+struct Event {
+    event_flags: u8,
+    _owner_slot: u8,
+    _fee_tier: u8,
+    _padding: [u8; 5],
+    native_qty_released: u64,
+    native_qty_paid: u64,
+    native_fee_or_rebate: u64,
+    _order_id: [u64; 2],
+    control: [u8; 32],
+    _client_order_id: u64,
+}
+
+/// The taker's side of a trade, in native units.
+#[derive(Debug, PartialEq)]
+pub struct Fill {
+    /// Sequence number of the event, which increases by one with every
+    /// event pushed to the queue.
+    pub seq: u64,
+    /// Control account of the taker.
+    pub control: Pubkey,
+    pub bid: bool,
+    pub base: u64,
+    /// Quote amount exchanged, without fees.
+    pub quote: u64,
+}
+
+/// Decodes the fills in a dex event queue, oldest first, along with the
+/// sequence numbers of the events still in it, whose end is the one the
+/// next event will get. Only the taker's fill is returned for each trade,
+/// as the maker's is the same trade again.
+pub fn event_queue_fills(data: &[u8]) -> Option<(Range<u64>, Vec<Fill>)> {
+    let data = data.get(HEAD_PADDING..data.len().checked_sub(TAIL_PADDING)?)?;
+    let header: Header =
+        bytemuck::pod_read_unaligned(data.get(..size_of::<Header>())?);
+    let events = &data[size_of::<Header>()..];
+    let capacity = (events.len() / size_of::<Event>()) as u64;
+    if capacity == 0 || header.count > capacity {
+        return None;
+    }
+
+    let first_seq = header.seq_num.checked_sub(header.count)?;
+    let fills = (0..header.count)
+        .filter_map(|i| {
+            let at =
+                ((header.head + i) % capacity) as usize * size_of::<Event>();
+            let e: Event = bytemuck::pod_read_unaligned(
+                &events[at..at + size_of::<Event>()],
+            );
+            if e.event_flags & FILL == 0 || e.event_flags & MAKER != 0 {
+                return None;
+            }
+            // A taker pays quote for base when buying and base for quote
+            // when selling, with the fee taken from the quote side.
+            let bid = e.event_flags & BID != 0;
+            let fee = e.native_fee_or_rebate;
+            let (base, quote) = match bid {
+                true => (
+                    e.native_qty_released,
+                    e.native_qty_paid.saturating_sub(fee),
+                ),
+                false => (e.native_qty_paid, e.native_qty_released + fee),
+            };
+            Some(Fill {
+                seq: first_seq + i,
+                control: Pubkey::new_from_array(e.control),
+                bid,
+                base,
+                quote,
+            })
+        })
+        .collect();
+    Some((first_seq..header.seq_num, fills))
+}
+
+/// Picks out the fills that are new in each read of an event queue, as
+/// every read holds the whole queue. The fills already in the queue on the
+/// first read aren't new.
+#[derive(Default)]
+pub struct NewFills {
+    next_seq: Option<u64>,
+}
+
+impl NewFills {
+    /// The fills in the queue `data` that weren't returned before, oldest
+    /// first, or `None` if the queue can't be decoded. Reads older than
+    /// the latest one return nothing.
+    pub fn read(&mut self, data: &[u8]) -> Option<Vec<Fill>> {
+        let (seqs, fills) = event_queue_fills(data)?;
+        let from = *self.next_seq.get_or_insert(seqs.end);
+        self.next_seq = Some(seqs.end.max(from));
+        Some(fills.into_iter().filter(|f| f.seq >= from).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A taker's fill of `base` for `quote`, before the fee of 1.
+    fn taker(bid: bool, base: u64, quote: u64) -> Event {
+        let mut e = Event::zeroed();
+        e.event_flags = FILL | if bid { BID } else { 0 };
+        e.native_fee_or_rebate = 1;
+        let (released, paid) = match bid {
+            true => (base, quote + 1),
+            false => (quote - 1, base),
+        };
+        e.native_qty_released = released;
+        e.native_qty_paid = paid;
+        e
+    }
+
+    fn maker(bid: bool) -> Event {
+        let mut e = taker(bid, 1, 1);
+        e.event_flags |= MAKER;
+        e
+    }
+
+    /// A queue of `slots` with `count` events from `head`, after
+    /// `seq_num` events were ever pushed.
+    fn queue(head: u64, count: u64, seq_num: u64, slots: &[Event]) -> Vec<u8> {
+        let header = Header {
+            _account_flags: 0,
+            head,
+            count,
+            seq_num,
+        };
+        let mut data = vec![0; HEAD_PADDING];
+        data.extend_from_slice(bytemuck::bytes_of(&header));
+        data.extend_from_slice(bytemuck::cast_slice(slots));
+        data.extend_from_slice(&[0; TAIL_PADDING]);
+        data
+    }
+
+    fn seqs(fills: &[Fill]) -> Vec<u64> {
+        fills.iter().map(|f| f.seq).collect()
+    }
+
+    #[test]
+    fn decodes_taker_fills() {
+        let slots = [taker(true, 2, 30), maker(false), taker(false, 5, 70)];
+        let (in_queue, fills) =
+            event_queue_fills(&queue(0, 3, 3, &slots)).unwrap();
+        assert_eq!(in_queue, 0..3);
+        let f = |seq, bid, base, quote| Fill {
+            seq,
+            control: Pubkey::default(),
+            bid,
+            base,
+            quote,
+        };
+        assert_eq!(fills, [f(0, true, 2, 30), f(2, false, 5, 70)]);
+    }
+
+    #[test]
+    fn decodes_wrapped_queue() {
+        // Events 7, 8 and 9 sit in slots 3, 0 and 1.
+        let slots = [
+            taker(true, 8, 1),
+            taker(true, 9, 1),
+            taker(true, 1, 1),
+            taker(true, 7, 1),
+        ];
+        let (in_queue, fills) =
+            event_queue_fills(&queue(3, 3, 10, &slots)).unwrap();
+        assert_eq!(in_queue, 7..10);
+        assert_eq!(seqs(&fills), [7, 8, 9]);
+        let bases: Vec<_> = fills.iter().map(|f| f.base).collect();
+        assert_eq!(bases, [7, 8, 9]);
+    }
+
+    #[test]
+    fn rejects_malformed_queues() {
+        let slots = [taker(true, 1, 1); 2];
+        assert!(event_queue_fills(&[]).is_none());
+        assert!(event_queue_fills(&queue(0, 0, 0, &[])).is_none());
+        assert!(event_queue_fills(&queue(0, 3, 3, &slots)).is_none());
+        assert!(event_queue_fills(&queue(0, 2, 1, &slots)).is_none());
+    }
+
+    #[test]
+    fn returns_only_new_fills() {
+        let slots = [taker(true, 1, 1); 8];
+        let mut new = NewFills::default();
+        // Fills already in the queue on connecting aren't new.
+        assert!(new.read(&queue(0, 2, 2, &slots)).unwrap().is_empty());
+        assert_eq!(seqs(&new.read(&queue(0, 4, 4, &slots)).unwrap()), [2, 3]);
+        // Consuming events doesn't bring any back.
+        assert!(new.read(&queue(2, 2, 4, &slots)).unwrap().is_empty());
+        // Neither does a stale read.
+        assert!(new.read(&queue(0, 2, 2, &slots)).unwrap().is_empty());
+        assert_eq!(
+            seqs(&new.read(&queue(2, 6, 8, &slots)).unwrap()),
+            [4, 5, 6, 7]
+        );
+        // The last two events wrap around to the first slots.
+        assert_eq!(seqs(&new.read(&queue(4, 6, 10, &slots)).unwrap()), [8, 9]);
+        assert!(new.read(&[]).is_none());
+    }
+}
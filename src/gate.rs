@@ -0,0 +1,206 @@
+use std::{num::NonZeroUsize, sync::Mutex, time::Duration};
+use tokio::{runtime::Handle, sync::Notify, time::Instant};
+
+/// Limits the number of in-flight RPC requests, adapting to the node's rate
+/// limits with an AIMD controller: the limit is halved whenever a request
+/// is rate limited, and otherwise grows by roughly one for every `limit`
+/// successful requests, up to the configured maximum. When the node says
+/// how long to wait with `Retry-After`, nothing is let through until then.
+pub struct RpcGate {
+    max: usize,
+    inner: Mutex<GateState>,
+    notify: Notify,
+}
+
+struct GateState {
+    limit: f64,
+    in_flight: usize,
+    paused_until: Option<Instant>,
+    /// Whether someone is asking the node how long to pause for.
+    probing: bool,
+}
+
+pub struct Permit<'a> {
+    gate: &'a RpcGate,
+}
+
+impl RpcGate {
+    pub fn new(max: NonZeroUsize) -> Self {
+        let max = max.get();
+        Self {
+            max,
+            inner: Mutex::new(GateState {
+                limit: max as f64,
+                in_flight: 0,
+                paused_until: None,
+                probing: false,
+            }),
+            notify: Notify::new(),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.inner.lock().unwrap().limit as usize
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.inner.lock().unwrap().in_flight
+    }
+
+    pub async fn acquire(&self) -> Permit<'_> {
+        loop {
+            // Registered before checking, so a release in between isn't
+            // missed.
+            let notified = self.notify.notified();
+            let paused_until = {
+                let mut st = self.inner.lock().unwrap();
+                let paused_until =
+                    st.paused_until.filter(|&t| t > Instant::now());
+                if paused_until.is_none() && st.in_flight < st.limit as usize {
+                    st.in_flight += 1;
+                    return Permit { gate: self };
+                }
+                paused_until
+            };
+            match paused_until {
+                Some(t) => tokio::time::sleep_until(t).await,
+                None => notified.await,
+            }
+        }
+    }
+
+    /// Gives back the permit held by the caller while `f` runs, so that
+    /// waiting between requests doesn't hold up others, then takes one
+    /// again. Must be called from blocking code run under a permit.
+    pub fn released<T>(&self, f: impl FnOnce() -> T) -> T {
+        drop(Permit { gate: self });
+        let r = f();
+        // The caller's permit is dropped by whoever acquired it.
+        std::mem::forget(Handle::current().block_on(self.acquire()));
+        r
+    }
+
+    /// Lets no request through for `d`, as asked by the node.
+    pub fn pause(&self, d: Duration) {
+        let until = Instant::now() + d;
+        let mut st = self.inner.lock().unwrap();
+        st.paused_until = Some(st.paused_until.map_or(until, |t| t.max(until)));
+    }
+
+    /// Whether the caller should ask the node how long to pause for after
+    /// being rate limited, which is the case for only one caller at a time
+    /// and not while already paused, so that a burst of rate limited
+    /// requests doesn't send a burst of extra ones. A `true` must be
+    /// followed by `end_probe`.
+    pub fn start_probe(&self) -> bool {
+        let mut st = self.inner.lock().unwrap();
+        let paused = st.paused_until.map_or(false, |t| t > Instant::now());
+        if st.probing || paused {
+            return false;
+        }
+        st.probing = true;
+        true
+    }
+
+    /// Ends a probe, pausing for `d` if the node asked to wait.
+    pub fn end_probe(&self, d: Option<Duration>) {
+        if let Some(d) = d {
+            self.pause(d);
+        }
+        self.inner.lock().unwrap().probing = false;
+    }
+
+    pub fn record(&self, rate_limited: bool) {
+        let mut st = self.inner.lock().unwrap();
+        st.limit = match rate_limited {
+            true => (st.limit / 2.).max(1.),
+            false => (st.limit + 1. / st.limit).min(self.max as f64),
+        };
+        drop(st);
+        self.notify.notify_waiters();
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.gate.inner.lock().unwrap().in_flight -= 1;
+        self.gate.notify.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    fn gate(max: usize) -> RpcGate {
+        RpcGate::new(NonZeroUsize::new(max).unwrap())
+    }
+
+    #[test]
+    fn halves_limit_when_rate_limited() {
+        let gate = gate(16);
+        gate.record(true);
+        assert_eq!(gate.limit(), 8);
+        for _ in 0..10 {
+            gate.record(true);
+        }
+        assert_eq!(gate.limit(), 1);
+    }
+
+    #[test]
+    fn recovers_limit_gradually() {
+        let gate = gate(4);
+        gate.record(true);
+        assert_eq!(gate.limit(), 2);
+        // Each success adds 1/limit, so it takes about `limit` of them to
+        // add one.
+        gate.record(false);
+        gate.record(false);
+        assert_eq!(gate.limit(), 2);
+        gate.record(false);
+        assert_eq!(gate.limit(), 3);
+        for _ in 0..100 {
+            gate.record(false);
+        }
+        assert_eq!(gate.limit(), 4);
+    }
+
+    #[test]
+    fn limits_requests_in_flight() {
+        let gate = gate(2);
+        let a = gate.acquire().now_or_never().unwrap();
+        let _b = gate.acquire().now_or_never().unwrap();
+        assert_eq!(gate.in_flight(), 2);
+        assert!(gate.acquire().now_or_never().is_none());
+        drop(a);
+        assert!(gate.acquire().now_or_never().is_some());
+
+        // Lowering the limit holds back requests until enough finish.
+        gate.record(true);
+        assert_eq!(gate.in_flight(), 1);
+        assert!(gate.acquire().now_or_never().is_none());
+    }
+
+    #[tokio::test]
+    async fn waits_out_pauses() {
+        let gate = gate(2);
+        let d = Duration::from_millis(50);
+        gate.pause(d);
+        assert!(gate.acquire().now_or_never().is_none());
+        let start = Instant::now();
+        let _permit = gate.acquire().await;
+        assert!(start.elapsed() >= d - Duration::from_millis(5));
+    }
+
+    #[test]
+    fn probes_once_per_pause() {
+        let gate = gate(2);
+        assert!(gate.start_probe());
+        assert!(!gate.start_probe());
+        gate.end_probe(None);
+        assert!(gate.start_probe());
+        gate.end_probe(Some(Duration::from_secs(60)));
+        assert!(!gate.start_probe());
+    }
+}
@@ -0,0 +1,55 @@
+use fixed::types::I80F48;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Rolling window of mark prices per market. The program doesn't keep any
+/// price history, so this is filled by sampling the cache periodically.
+pub struct MarkHistory {
+    window: Duration,
+    samples: Mutex<HashMap<String, VecDeque<(Instant, I80F48)>>>,
+}
+
+pub struct MarkSummary {
+    pub open: I80F48,
+    pub high: I80F48,
+    pub low: I80F48,
+    /// How far back the samples go, at most the window.
+    pub span: Duration,
+}
+
+impl MarkHistory {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            samples: Mutex::default(),
+        }
+    }
+
+    pub fn record(&self, symbol: String, price: I80F48) {
+        let now = Instant::now();
+        let mut samples = self.samples.lock().unwrap();
+        let v = samples.entry(symbol).or_default();
+        v.push_back((now, price));
+        while matches!(v.front(), Some((t, _)) if now - *t > self.window) {
+            v.pop_front();
+        }
+    }
+
+    pub fn summary(&self, symbol: &str) -> Option<MarkSummary> {
+        let samples = self.samples.lock().unwrap();
+        let v = samples.get(symbol)?;
+        let &(since, open) = v.front()?;
+        let (high, low) = v
+            .iter()
+            .fold((open, open), |(h, l), &(_, p)| (h.max(p), l.min(p)));
+        Some(MarkSummary {
+            open,
+            high,
+            low,
+            span: since.elapsed(),
+        })
+    }
+}
@@ -1,13 +1,37 @@
 mod api;
+mod cache;
 mod error;
+mod events;
+mod gate;
+mod history;
+mod pubsub;
+mod signer;
 mod state;
+mod symbol;
 
+pub use cache::*;
 pub use error::*;
+pub use events::*;
+pub use gate::*;
+pub use history::*;
+pub use pubsub::*;
+pub use signer::*;
 pub use state::*;
+pub use symbol::*;
 
-use actix_web::{middleware, web::Data, App, HttpServer};
-use anchor_client::solana_sdk::signer::keypair;
+use actix_web::{
+    middleware,
+    web::{self, Data},
+    App, HttpServer,
+};
+use anchor_client::solana_sdk::{
+    commitment_config::CommitmentLevel, pubkey::Pubkey, signer::keypair,
+};
 use clap::Parser;
+use std::{
+    num::{NonZeroU64, NonZeroUsize},
+    sync::Arc,
+};
 use zo_abi as zo;
 
 #[derive(Parser)]
@@ -18,7 +42,55 @@ struct Cli {
 
     /// Path to the payer keypair.
     #[clap(short, long)]
-    payer: std::path::PathBuf,
+    payer: Option<std::path::PathBuf>,
+
+    /// URL of a remote signing service to use instead of a local keypair.
+    #[clap(long, env = "SIGNER_URL")]
+    signer_url: Option<String>,
+
+    /// Public key of the remote signer's keypair.
+    #[clap(long, env = "SIGNER_PUBKEY")]
+    signer_pubkey: Option<Pubkey>,
+
+    /// Maximum number of RPC requests in flight at once. The limit is
+    /// lowered automatically while the RPC node is rate limiting.
+    #[clap(long, env = "MAX_RPC_CONCURRENCY", default_value = "16")]
+    max_rpc_concurrency: NonZeroUsize,
+
+    /// Number of resting orders an order may match against when the
+    /// request doesn't set `limit`. Higher values fill large orders against
+    /// deep books better, at the cost of more compute units per order.
+    #[clap(long, env = "DEFAULT_ORDER_LIMIT", default_value = "20")]
+    default_order_limit: u16,
+
+    /// Commitment that sent transactions are confirmed at, unless a request
+    /// asks for another one.
+    #[clap(long, env = "CONFIRM_COMMITMENT", default_value = "finalized")]
+    confirm_commitment: CommitmentLevel,
+
+    /// Seconds to wait for a sent transaction to be confirmed, unless a
+    /// request asks for another timeout.
+    #[clap(long, env = "CONFIRM_TIMEOUT", default_value = "60")]
+    confirm_timeout: u64,
+
+    /// Margin, in percent of notional, above the maintenance margin
+    /// fraction below which reads of the account warn that it's close to
+    /// liquidation.
+    #[clap(long, env = "HEALTH_WARNING_THRESHOLD", default_value = "10")]
+    health_warning_threshold: f64,
+
+    /// Commitment that the zo state is read at on startup.
+    #[clap(long, env = "STARTUP_COMMITMENT", default_value = "confirmed")]
+    startup_commitment: CommitmentLevel,
+
+    /// Seconds between samples of the mark prices used for market stats.
+    #[clap(long, env = "STATS_INTERVAL", default_value = "60")]
+    stats_interval: NonZeroU64,
+
+    /// Milliseconds that reads of the margin, control and cache accounts
+    /// are reused for. Zero disables caching.
+    #[clap(long, env = "ACCOUNT_CACHE_TTL", default_value = "1000")]
+    account_cache_ttl: u64,
 }
 
 #[actix_web::main]
@@ -26,19 +98,67 @@ async fn main() {
     dotenv::dotenv().ok();
     env_logger::init();
 
-    let Cli { cluster, payer } = Cli::parse();
+    let Cli {
+        cluster,
+        payer,
+        signer_url,
+        signer_pubkey,
+        max_rpc_concurrency,
+        default_order_limit,
+        confirm_commitment,
+        confirm_timeout,
+        health_warning_threshold,
+        stats_interval,
+        account_cache_ttl,
+        startup_commitment,
+    } = Cli::parse();
+    // The payer keypair itself, for when mounting a keypair file is
+    // awkward. It's only read from the environment so that the secret
+    // doesn't end up in the process's arguments.
+    let payer_keypair = std::env::var("PAYER_KEYPAIR").ok();
 
-    let payer = keypair::read_keypair_file(&payer).unwrap_or_else(|_| {
-        panic!("Failed to read keypair from {}", payer.to_string_lossy());
-    });
-    let payer_bytes = payer.to_bytes();
+    // A pubkey without a URL most likely means the URL was mistyped, so
+    // rather than signing with whatever else was given, refuse to start.
+    if signer_pubkey.is_some() && signer_url.is_none() {
+        panic!("--signer-pubkey was given without --signer-url");
+    }
+
+    let signer: Arc<dyn TxSigner> = match (payer, payer_keypair, signer_url) {
+        (Some(payer), None, None) => {
+            Arc::new(keypair::read_keypair_file(&payer).unwrap_or_else(|_| {
+                panic!(
+                    "Failed to read keypair from {}",
+                    payer.to_string_lossy()
+                );
+            }))
+        }
+        (None, Some(secret), None) => Arc::new(
+            parse_keypair(&secret).expect("Failed to parse PAYER_KEYPAIR"),
+        ),
+        (None, None, Some(url)) => {
+            let pubkey = signer_pubkey
+                .expect("--signer-pubkey is required for a remote signer");
+            // The blocking HTTP client can't be built on the runtime.
+            Arc::new(
+                tokio::task::spawn_blocking(move || {
+                    RemoteSigner::new(url, pubkey)
+                })
+                .await
+                .unwrap(),
+            )
+        }
+        _ => panic!(
+            "Exactly one of --payer, PAYER_KEYPAIR or --signer-url must be \
+             given"
+        ),
+    };
 
     let zo_state = {
         let cluster = cluster.clone();
         tokio::task::spawn_blocking(move || {
             use anchor_client::{
                 solana_sdk::{
-                    commitment_config::CommitmentConfig, pubkey::Pubkey,
+                    commitment_config::CommitmentConfig,
                     signer::null_signer::NullSigner,
                 },
                 Client,
@@ -46,7 +166,9 @@ async fn main() {
             let client = Client::new_with_options(
                 cluster.clone(),
                 std::rc::Rc::new(NullSigner::new(&Pubkey::default())),
-                CommitmentConfig::processed(),
+                CommitmentConfig {
+                    commitment: startup_commitment,
+                },
             );
             let program = client.program(zo::ID);
             program.account::<zo::State>(zo::ZO_STATE_ID).unwrap()
@@ -55,6 +177,45 @@ async fn main() {
         .unwrap()
     };
 
+    // Like the remote signer's, it can't be built on the runtime.
+    let http = tokio::task::spawn_blocking(reqwest::blocking::Client::new)
+        .await
+        .unwrap();
+
+    // Shared between workers so that the RPC gate applies to the whole
+    // service.
+    let st = Data::new(State::new(
+        cluster,
+        signer,
+        zo_state,
+        http,
+        Config {
+            max_rpc_concurrency,
+            default_order_limit,
+            confirm_commitment,
+            confirm_timeout: std::time::Duration::from_secs(confirm_timeout),
+            health_warning_threshold: health_warning_threshold / 100.,
+            account_cache_ttl: std::time::Duration::from_millis(
+                account_cache_ttl,
+            ),
+        },
+    ));
+
+    {
+        let st = st.clone();
+        actix_web::rt::spawn(async move {
+            let mut interval = tokio::time::interval(
+                std::time::Duration::from_secs(stats_interval.get()),
+            );
+            loop {
+                interval.tick().await;
+                if let Err(e) = st.sample_marks().await {
+                    log::warn!("Failed to sample mark prices: {}", e);
+                }
+            }
+        });
+    }
+
     HttpServer::new(move || {
         App::new()
             .wrap(middleware::NormalizePath::trim())
@@ -65,18 +226,19 @@ async fn main() {
             .wrap(middleware::Logger::new(
                 "%a \"%r\" %s %b \"%{Referer}i\" \"%{User-Agent}i\" %Dms",
             ))
-            .app_data(Data::new(State::new(
-                cluster.clone(),
-                &keypair::Keypair::from_bytes(&payer_bytes).unwrap(),
-                zo_state,
-            )))
-            .service(api::collateral_balances)
-            .service(api::collateral_deposit)
-            .service(api::collateral_withdraw)
-            .service(api::position)
-            .service(api::orders)
-            .service(api::orders_post)
-            .service(api::orders_delete)
+            .app_data(st.clone())
+            .service(api::metrics)
+            .service(api::openapi)
+            .service(web::scope("/v1").configure(api::routes))
+            // Unprefixed routes are deprecated aliases of the /v1 ones.
+            .service(
+                web::scope("")
+                    .wrap(
+                        middleware::DefaultHeaders::new()
+                            .add(("Deprecation", "true")),
+                    )
+                    .configure(api::routes),
+            )
     })
     .bind(format!(
         "0.0.0.0:{}",
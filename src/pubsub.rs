@@ -0,0 +1,179 @@
+use anchor_client::{
+    solana_client::{
+        pubsub_client::PubsubClient, rpc_config::RpcAccountInfoConfig,
+    },
+    solana_sdk::{
+        account::Account, commitment_config::CommitmentConfig, pubkey::Pubkey,
+    },
+};
+use solana_account_decoder::UiAccountEncoding;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+use tokio::sync::broadcast;
+
+const MIN_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Account updates a subscriber may fall behind by before missing some.
+const CHANNEL_CAPACITY: usize = 16;
+
+/// Account subscriptions over the cluster's websocket, shared between
+/// consumers. Each subscribed account has one connection, which is
+/// re-established with exponential backoff when it drops, and whose
+/// updates are broadcast to every consumer. A subscription is closed once
+/// all of its receivers are dropped.
+///
+/// The RPC client in use only has a blocking pubsub client, so every
+/// connection runs on its own thread.
+pub struct Pubsub {
+    url: String,
+    commitment: CommitmentConfig,
+    subs: Mutex<HashMap<Pubkey, broadcast::Sender<Arc<Account>>>>,
+}
+
+impl Pubsub {
+    pub fn new(url: String, commitment: CommitmentConfig) -> Self {
+        Self {
+            url,
+            commitment,
+            subs: Mutex::default(),
+        }
+    }
+
+    /// Registers interest in the account at `k`. Dropping the receiver
+    /// deregisters it.
+    pub fn subscribe(
+        self: &Arc<Self>,
+        k: Pubkey,
+    ) -> broadcast::Receiver<Arc<Account>> {
+        let mut subs = self.subs.lock().unwrap();
+        if let Some(tx) = subs.get(&k) {
+            return tx.subscribe();
+        }
+        let (tx, rx) = broadcast::channel(CHANNEL_CAPACITY);
+        subs.insert(k, tx.clone());
+        let this = self.clone();
+        thread::spawn(move || this.run(k, tx));
+        rx
+    }
+
+    /// Number of accounts currently subscribed to.
+    pub fn subscriptions(&self) -> usize {
+        self.subs.lock().unwrap().len()
+    }
+
+    /// Removes the subscription to `k` if nobody is listening anymore.
+    /// Checked under the lock so that a concurrent `subscribe` either
+    /// reuses the connection or starts a new one.
+    fn close_if_unused(&self, k: &Pubkey) -> bool {
+        let mut subs = self.subs.lock().unwrap();
+        let unused = subs.get(k).map_or(true, |tx| tx.receiver_count() == 0);
+        if unused {
+            subs.remove(k);
+        }
+        unused
+    }
+
+    fn run(&self, k: Pubkey, tx: broadcast::Sender<Arc<Account>>) {
+        let config = RpcAccountInfoConfig {
+            encoding: Some(UiAccountEncoding::Base64),
+            commitment: Some(self.commitment),
+            ..Default::default()
+        };
+        let mut backoff = MIN_BACKOFF;
+        while !self.close_if_unused(&k) {
+            let res =
+                PubsubClient::account_subscribe(&self.url, &k, Some(config));
+            let (mut sub, rx) = match res {
+                Ok(x) => x,
+                Err(e) => {
+                    log::warn!("Failed to subscribe to {}: {}", k, e);
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                    continue;
+                }
+            };
+            backoff = MIN_BACKOFF;
+            loop {
+                // Wakes up regularly to notice when everyone unsubscribed.
+                match rx.recv_timeout(Duration::from_secs(1)) {
+                    Ok(res) => {
+                        if let Some(a) = res.value.decode::<Account>() {
+                            // Fails only if there are no receivers, which is
+                            // handled on the next timeout.
+                            let _ = tx.send(Arc::new(a));
+                        }
+                    }
+                    Err(e) if e.is_timeout() => {
+                        if tx.receiver_count() == 0 {
+                            break;
+                        }
+                    }
+                    Err(_) => {
+                        log::warn!("Subscription to {} dropped", k);
+                        break;
+                    }
+                }
+            }
+            let _ = sub.shutdown();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Nothing listens on the URL, so subscriptions keep retrying in the
+    /// background without ever connecting.
+    fn pubsub() -> Arc<Pubsub> {
+        Arc::new(Pubsub::new(
+            "ws://127.0.0.1:1".into(),
+            CommitmentConfig::confirmed(),
+        ))
+    }
+
+    /// Whether the subscription count reaches `n` within a few retries of
+    /// the connections, which is when unused ones are noticed.
+    fn settles_at(pubsub: &Pubsub, n: usize) -> bool {
+        let start = std::time::Instant::now();
+        while start.elapsed() < Duration::from_secs(10) {
+            if pubsub.subscriptions() == n {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        false
+    }
+
+    #[test]
+    fn shares_subscriptions() {
+        let pubsub = pubsub();
+        let k = Pubkey::new_unique();
+        let _a = pubsub.subscribe(k);
+        let _b = pubsub.subscribe(k);
+        assert_eq!(pubsub.subscriptions(), 1);
+        assert_eq!(pubsub.subs.lock().unwrap()[&k].receiver_count(), 2);
+
+        let _c = pubsub.subscribe(Pubkey::new_unique());
+        assert_eq!(pubsub.subscriptions(), 2);
+    }
+
+    #[test]
+    fn closes_unused_subscriptions() {
+        let pubsub = pubsub();
+        let k = Pubkey::new_unique();
+        let a = pubsub.subscribe(k);
+        let b = pubsub.subscribe(k);
+        drop(a);
+        thread::sleep(MIN_BACKOFF * 3);
+        assert_eq!(pubsub.subscriptions(), 1);
+
+        drop(b);
+        assert!(settles_at(&pubsub, 0));
+    }
+}
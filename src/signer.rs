@@ -0,0 +1,132 @@
+use crate::Error;
+use anchor_client::solana_sdk::{
+    pubkey::Pubkey,
+    signature::{Keypair, Signature, Signer},
+};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// Signs transaction messages on behalf of the authority.
+pub trait TxSigner: Send + Sync {
+    fn pubkey(&self) -> Pubkey;
+    fn sign_message(&self, message: &[u8]) -> Result<Signature, Error>;
+}
+
+impl TxSigner for Keypair {
+    fn pubkey(&self) -> Pubkey {
+        Signer::pubkey(self)
+    }
+
+    fn sign_message(&self, message: &[u8]) -> Result<Signature, Error> {
+        Ok(Signer::sign_message(self, message))
+    }
+}
+
+/// Parses a keypair given either in base58 or as a JSON array of bytes, the
+/// format of keypair files.
+pub fn parse_keypair(s: &str) -> Option<Keypair> {
+    let s = s.trim();
+    let bytes = match s.starts_with('[') {
+        true => serde_json::from_str::<Vec<u8>>(s).ok()?,
+        false => bs58::decode(s).into_vec().ok()?,
+    };
+    Keypair::from_bytes(&bytes).ok()
+}
+
+/// Requests signatures from an HTTP signing service, so that the private
+/// key never has to be on this server.
+///
+/// The service is sent `POST <url>` with `{ "pubkey", "message" }` and
+/// must respond with `{ "signature" }`, all base58 encoded.
+pub struct RemoteSigner {
+    url: String,
+    pubkey: Pubkey,
+    /// Kept so that connections to the service are reused.
+    client: reqwest::blocking::Client,
+}
+
+#[derive(Serialize)]
+struct SignReq {
+    pubkey: String,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct SignResp {
+    signature: String,
+}
+
+impl RemoteSigner {
+    pub fn new(url: String, pubkey: Pubkey) -> Self {
+        Self {
+            url,
+            pubkey,
+            client: reqwest::blocking::Client::new(),
+        }
+    }
+
+    fn request(&self, message: &[u8]) -> reqwest::Result<SignResp> {
+        self.client
+            .post(&self.url)
+            .json(&SignReq {
+                pubkey: self.pubkey.to_string(),
+                message: bs58::encode(message).into_string(),
+            })
+            .send()?
+            .error_for_status()?
+            .json()
+    }
+}
+
+impl TxSigner for RemoteSigner {
+    fn pubkey(&self) -> Pubkey {
+        self.pubkey
+    }
+
+    fn sign_message(&self, message: &[u8]) -> Result<Signature, Error> {
+        let resp = self
+            .request(message)
+            .map_err(|e| Error::Signer(e.to_string()))?;
+        let sig = Signature::from_str(&resp.signature)
+            .map_err(|e| Error::Signer(e.to_string()))?;
+        match sig.verify(self.pubkey.as_ref(), message) {
+            true => Ok(sig),
+            false => Err(Error::Signer(format!(
+                "Invalid signature from {}",
+                self.url
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_keypairs() {
+        let k = Keypair::new();
+        let json = format!("{:?}\n", k.to_bytes().to_vec());
+        let base58 = format!(" {} ", k.to_base58_string());
+        for s in [json, base58] {
+            let parsed = parse_keypair(&s).unwrap();
+            assert_eq!(parsed.to_bytes(), k.to_bytes(), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_keypairs() {
+        let k = Keypair::new().to_bytes();
+        for s in [
+            String::new(),
+            "[]".to_owned(),
+            format!("{:?}", &k[..63]),
+            format!("[256{}]", ", 0".repeat(63)),
+            "[1, 2,]".to_owned(),
+            bs58::encode(&k[..32]).into_string(),
+            "not base58: 0OIl".to_owned(),
+        ] {
+            assert!(parse_keypair(&s).is_none(), "{:?}", s);
+        }
+    }
+}
@@ -1,67 +1,315 @@
-use crate::Error;
+use crate::{AccountCache, Error, MarkHistory, Pubsub, RpcGate, TxSigner};
 use anchor_client::{
-    solana_client::rpc_client::RpcClient,
+    anchor_lang::AccountDeserialize,
+    solana_client::{
+        client_error::ClientError as SolanaClientError,
+        rpc_client::RpcClient,
+        rpc_config::{
+            RpcSendTransactionConfig, RpcSimulateTransactionConfig,
+            RpcTransactionConfig,
+        },
+    },
     solana_sdk::{
-        commitment_config::CommitmentConfig,
+        account::Account,
+        commitment_config::{CommitmentConfig, CommitmentLevel},
+        instruction::{Instruction, InstructionError},
+        packet::PACKET_DATA_SIZE,
         pubkey::Pubkey,
-        signer::{keypair::Keypair, Signer as _},
+        signature::Signature,
+        signer::null_signer::NullSigner,
+        transaction::{Transaction, TransactionError},
+    },
+    Client, ClientError, Cluster, Program, RequestBuilder,
+};
+use serde::Deserialize;
+use solana_transaction_status::UiTransactionEncoding;
+use std::{
+    collections::{HashSet, VecDeque},
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
     },
-    Client, Cluster, Program,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
+use utoipa::ToSchema;
 use zo_abi as zo;
 
+#[derive(Clone)]
 pub struct State {
-    payer: Keypair,
+    signer: Arc<dyn TxSigner>,
     cluster: Cluster,
     commitment: CommitmentConfig,
+    /// Commitment that sent transactions are waited on to reach, kept apart
+    /// from the client's as it may be lower than the node serves some
+    /// requests at.
+    confirm_commitment: CommitmentConfig,
+    confirm_timeout: Duration,
+    read_commitment: CommitmentConfig,
+    rpc_gate: Arc<RpcGate>,
+    /// Client for the requests to the RPC node that the RPC client can't
+    /// make.
+    http: reqwest::blocking::Client,
+    pubsub: Arc<Pubsub>,
+    client_ids: Arc<ClientIds>,
+    mark_history: Arc<MarkHistory>,
+    account_cache: Arc<AccountCache>,
     zo_state: zo::State,
     pub zo_state_signer: Pubkey,
     pub zo_margin_key: Pubkey,
+    pub default_order_limit: u16,
+    pub health_warning_threshold: f64,
+}
+
+/// Settings of the service, from the command line or environment.
+pub struct Config {
+    pub max_rpc_concurrency: NonZeroUsize,
+    pub default_order_limit: u16,
+    pub confirm_commitment: CommitmentLevel,
+    pub confirm_timeout: Duration,
+    /// Margin fraction above the maintenance margin fraction below which
+    /// reads warn that the account is close to liquidation.
+    pub health_warning_threshold: f64,
+    /// How long reads of the margin, control and cache accounts are reused.
+    pub account_cache_ttl: Duration,
+}
+
+/// Per-request overrides for how transactions are submitted. The defaults
+/// match the RPC client's own: preflight on, at the client's commitment,
+/// and waiting for confirmation with the configured timeout and commitment.
+#[derive(Deserialize, ToSchema, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct SendOptions {
+    #[serde(default)]
+    pub skip_preflight: bool,
+    #[schema(value_type = Option<String>)]
+    pub preflight_commitment: Option<CommitmentLevel>,
+    /// Look up how many compute units the transaction consumed.
+    #[serde(default)]
+    pub return_compute_units: bool,
+    /// Wait for the transaction to be confirmed, true if not set.
+    pub confirm: Option<bool>,
+    /// Seconds to wait for confirmation.
+    pub confirm_timeout: Option<u64>,
+    #[schema(value_type = Option<String>)]
+    pub confirm_commitment: Option<CommitmentLevel>,
+}
+
+/// Client ids of orders that are currently being placed.
+struct ClientIds {
+    next: AtomicU64,
+    in_flight: Mutex<HashSet<u64>>,
 }
 
-impl Clone for State {
-    fn clone(&self) -> Self {
+impl ClientIds {
+    fn new() -> Self {
+        // Seeding from the clock keeps generated ids unique across restarts.
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
         Self {
-            payer: self.payer(),
-            cluster: self.cluster.clone(),
-            commitment: self.commitment,
-            zo_state: self.zo_state,
-            zo_state_signer: self.zo_state_signer.clone(),
-            zo_margin_key: self.zo_margin_key.clone(),
+            next: AtomicU64::new(now.as_micros() as u64),
+            in_flight: Mutex::default(),
         }
     }
 }
 
+/// Holds a client id reserved with [`State::reserve_client_id`], releasing
+/// it when dropped.
+pub struct ClientId {
+    ids: Arc<ClientIds>,
+    pub id: u64,
+}
+
+impl Drop for ClientId {
+    fn drop(&mut self) {
+        self.ids.in_flight.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// A transaction that was sent, and whether it was confirmed.
+pub struct Sent {
+    pub sig: Signature,
+    pub confirmed: bool,
+    pub compute_units: Option<u64>,
+}
+
+/// Waits for a transaction to reach `commitment`. Returns whether it did
+/// before the timeout, or its error if it failed. The caller's permit is
+/// given back to `gate` between polls.
+fn confirm(
+    rpc: &RpcClient,
+    gate: &RpcGate,
+    sig: &Signature,
+    commitment: CommitmentConfig,
+    timeout: Duration,
+) -> Result<bool, Error> {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        match rpc.get_signature_status_with_commitment(sig, commitment)? {
+            Some(Ok(())) => return Ok(true),
+            Some(Err(e)) => return Err(SolanaClientError::from(e).into()),
+            None => {
+                gate.released(|| std::thread::sleep(Duration::from_millis(500)))
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// How long the RPC node at `url` asks to wait before the next request,
+/// from the `Retry-After` header of a probe. The RPC client already waits
+/// as asked between its own retries, but the header is lost once it gives
+/// up and returns the error.
+fn retry_after(
+    http: &reqwest::blocking::Client,
+    url: &str,
+) -> Option<Duration> {
+    let resp = http
+        .post(url)
+        .json(&serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "getHealth",
+        }))
+        .send()
+        .ok()?;
+    if resp.status() != reqwest::StatusCode::TOO_MANY_REQUESTS {
+        return None;
+    }
+    let secs = resp.headers().get(reqwest::header::RETRY_AFTER)?;
+    let secs: u64 = secs.to_str().ok()?.trim().parse().ok()?;
+    // Same cap as the RPC client's, against a misconfigured node.
+    Some(Duration::from_secs(secs.min(120)))
+}
+
+/// Log messages of a transaction confirmed at `commitment`, which can't
+/// be `processed` as the node only serves confirmed transactions.
+fn logs(
+    rpc: &RpcClient,
+    sig: &Signature,
+    commitment: CommitmentConfig,
+) -> Result<Vec<String>, Error> {
+    let tx = rpc.get_transaction_with_config(
+        sig,
+        RpcTransactionConfig {
+            encoding: Some(UiTransactionEncoding::Json),
+            commitment: Some(commitment),
+        },
+    )?;
+    Ok(tx
+        .transaction
+        .meta
+        .and_then(|m| m.log_messages)
+        .unwrap_or_default())
+}
+
+/// Sums the compute units consumed by the top-level instructions of a
+/// confirmed transaction, as reported in its logs. The transaction meta of
+/// this Solana version doesn't carry the total, and the logs may be
+/// truncated for long transactions, in which case this is short.
+fn compute_units(
+    rpc: &RpcClient,
+    sig: &Signature,
+    commitment: CommitmentConfig,
+) -> Result<u64, Error> {
+    let (mut depth, mut units) = (0, 0);
+    for l in logs(rpc, sig, commitment)? {
+        match l.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["Program", _, "invoke", _] => depth += 1,
+            ["Program", _, "success"] | ["Program", _, "failed:", ..] => {
+                depth -= 1
+            }
+            ["Program", _, "consumed", n, "of", _, "compute", "units"]
+                if depth == 1 =>
+            {
+                units += n.parse::<u64>().unwrap_or(0)
+            }
+            _ => {}
+        }
+    }
+    Ok(units)
+}
+
+/// The trader's accounts together with the cache, all read in a single
+/// `getMultipleAccounts` call so that they reflect the same slot.
+pub struct Snapshot {
+    pub slot: u64,
+    pub cache: zo::Cache,
+    pub margin: zo::Margin,
+    pub control: zo::Control,
+}
+
+fn deserialize<T: AccountDeserialize>(a: Option<Account>) -> Result<T, Error> {
+    let a = a.ok_or(ClientError::AccountNotFound)?;
+    T::try_deserialize(&mut a.data.as_slice())
+        .map_err(|e| ClientError::from(e).into())
+}
+
+/// The accounts that nearly every request reads, and so are kept in the
+/// account cache. Other accounts are always read from the node.
+trait Cached: AccountDeserialize {}
+
+impl Cached for zo::Cache {}
+impl Cached for zo::Margin {}
+impl Cached for zo::Control {}
+
+fn margin_key(owner: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(
+        &[owner.as_ref(), zo::ZO_STATE_ID.as_ref(), b"marginv1"],
+        &zo::ID,
+    )
+    .0
+}
+
 impl State {
-    pub fn new(cluster: Cluster, payer: &Keypair, zo_state: zo::State) -> Self {
+    pub fn new(
+        cluster: Cluster,
+        signer: Arc<dyn TxSigner>,
+        zo_state: zo::State,
+        http: reqwest::blocking::Client,
+        config: Config,
+    ) -> Self {
         let (zo_state_signer, _) =
             Pubkey::find_program_address(&[zo::ZO_STATE_ID.as_ref()], &zo::ID);
 
-        let (zo_margin_key, _) = Pubkey::find_program_address(
-            &[
-                payer.pubkey().as_ref(),
-                zo::ZO_STATE_ID.as_ref(),
-                b"marginv1",
-            ],
-            &zo::ID,
-        );
+        let zo_margin_key = margin_key(&signer.pubkey());
 
+        let ws_url = cluster.ws_url().to_string();
         Self {
-            payer: Keypair::from_bytes(&payer.to_bytes()).unwrap(),
+            signer,
             cluster,
             commitment: CommitmentConfig::finalized(),
+            confirm_commitment: CommitmentConfig {
+                commitment: config.confirm_commitment,
+            },
+            confirm_timeout: config.confirm_timeout,
+            read_commitment: CommitmentConfig::confirmed(),
+            rpc_gate: Arc::new(RpcGate::new(config.max_rpc_concurrency)),
+            http,
+            pubsub: Arc::new(Pubsub::new(
+                ws_url,
+                CommitmentConfig::confirmed(),
+            )),
+            client_ids: Arc::new(ClientIds::new()),
+            mark_history: Arc::new(MarkHistory::new(Duration::from_secs(
+                24 * 60 * 60,
+            ))),
+            account_cache: Arc::new(AccountCache::new(
+                config.account_cache_ttl,
+            )),
             zo_state,
             zo_state_signer,
             zo_margin_key,
+            default_order_limit: config.default_order_limit,
+            health_warning_threshold: config.health_warning_threshold,
         }
     }
 
-    fn market_symbol_index(&self, s: &str) -> Result<usize, Error> {
+    pub fn market_symbol_index(&self, s: &str) -> Result<usize, Error> {
         self.zo_state
             .perp_markets
             .iter()
             .map(|m| String::from(m.symbol))
-            .position(|x| x == s)
+            .position(|x| x.eq_ignore_ascii_case(s))
             .ok_or_else(|| Error::MarketSymbolNotFound(s.to_owned()))
     }
 
@@ -70,22 +318,20 @@ impl State {
             .collaterals
             .iter()
             .map(|m| String::from(m.oracle_symbol))
-            .position(|x| x == s)
+            .position(|x| x.eq_ignore_ascii_case(s))
             .ok_or_else(|| Error::CollateralSymbolNotFound(s.to_owned()))
     }
 
-    fn payer(&self) -> Keypair {
-        Keypair::from_bytes(&self.payer.to_bytes()).unwrap()
-    }
-
     pub fn authority(&self) -> Pubkey {
-        self.payer.pubkey()
+        self.signer.pubkey()
     }
 
     pub fn client(&self) -> Client {
         Client::new_with_options(
             self.cluster.clone(),
-            std::rc::Rc::new(self.payer()),
+            // Transactions are signed in `send`, the client only needs to
+            // know who the payer is.
+            std::rc::Rc::new(NullSigner::new(&self.authority())),
             self.commitment,
         )
     }
@@ -94,10 +340,310 @@ impl State {
         self.client().program(zo::ID)
     }
 
+    /// Reserves a client id for an order being placed, generating a fresh
+    /// one if none (or zero) is given. Fails if the id is already in flight.
+    pub fn reserve_client_id(
+        &self,
+        id: Option<u64>,
+    ) -> Result<ClientId, Error> {
+        let id = match id {
+            Some(id) if id != 0 => id,
+            _ => self.client_ids.next.fetch_add(1, Ordering::Relaxed),
+        };
+        match self.client_ids.in_flight.lock().unwrap().insert(id) {
+            true => Ok(ClientId {
+                ids: self.client_ids.clone(),
+                id,
+            }),
+            false => Err(Error::DuplicateClientId(id)),
+        }
+    }
+
     pub fn rpc(&self) -> RpcClient {
         self.program().rpc()
     }
 
+    pub fn rpc_gate(&self) -> &RpcGate {
+        &self.rpc_gate
+    }
+
+    pub fn pubsub(&self) -> &Arc<Pubsub> {
+        &self.pubsub
+    }
+
+    /// Runs `f`, which makes blocking RPC calls, on the blocking thread
+    /// pool once the RPC gate lets it through.
+    pub async fn with_rpc<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        T: 'static + std::marker::Send,
+        F: 'static + std::marker::Send + FnOnce(State) -> Result<T, Error>,
+    {
+        let permit = self.rpc_gate.acquire().await;
+        let st = self.clone();
+        let r = tokio::task::spawn_blocking(move || f(st)).await.unwrap();
+        drop(permit);
+        let rate_limited = matches!(r, Err(ref e) if e.is_rate_limited());
+        self.rpc_gate.record(rate_limited);
+        if rate_limited && self.rpc_gate.start_probe() {
+            let (http, url) =
+                (self.http.clone(), self.cluster.url().to_owned());
+            let wait =
+                tokio::task::spawn_blocking(move || retry_after(&http, &url));
+            self.rpc_gate.end_probe(wait.await.unwrap());
+        }
+        r.map_err(Error::detect_node_behind)
+    }
+
+    /// Signs the transaction built by `req` with the configured signer and
+    /// sends it, then waits for it to be confirmed.
+    pub fn send(
+        &self,
+        req: RequestBuilder,
+        opts: SendOptions,
+    ) -> Result<Sent, Error> {
+        let ixs = req.instructions()?;
+        let size = self.tx_size(&ixs);
+        if size > PACKET_DATA_SIZE {
+            return Err(Error::TransactionTooLarge(size));
+        }
+        self.send_instructions(&self.rpc(), &ixs, opts)
+    }
+
+    /// Sends `ixs` in as few transactions as they fit in, both in size and
+    /// in compute units, keeping their order. If `atomic`, fails without
+    /// sending anything when they don't fit in one.
+    ///
+    /// The transactions are sent one after the other, so if one fails, the
+    /// ones before it have already been sent, and are returned in
+    /// `Error::PartiallySent`.
+    pub fn send_all(
+        &self,
+        ixs: Vec<Instruction>,
+        atomic: bool,
+        opts: SendOptions,
+    ) -> Result<Vec<Sent>, Error> {
+        let rpc = self.rpc();
+        let batches = self.split(&rpc, ixs, atomic)?;
+        if atomic && batches.len() > 1 {
+            return Err(Error::NotAtomic(batches.len()));
+        }
+        let mut sent = Vec::new();
+        for ixs in &batches {
+            match self.send_instructions(&rpc, ixs, opts) {
+                Ok(x) => sent.push(x),
+                Err(e) if sent.is_empty() => return Err(e),
+                Err(e) => {
+                    return Err(Error::PartiallySent {
+                        sigs: sent.into_iter().map(|x| x.sig).collect(),
+                        source: Box::new(e),
+                    })
+                }
+            }
+        }
+        Ok(sent)
+    }
+
+    /// Serialized size of a transaction with `ixs`: the signature count and
+    /// the authority's signature, then the message.
+    fn tx_size(&self, ixs: &[Instruction]) -> usize {
+        let tx = Transaction::new_with_payer(ixs, Some(&self.authority()));
+        1 + 64 + tx.message_data().len()
+    }
+
+    /// Whether `ixs` run out of compute units in one transaction, judged by
+    /// simulating it. Other simulation errors are left for sending to
+    /// report.
+    fn exceeds_compute(
+        &self,
+        rpc: &RpcClient,
+        ixs: &[Instruction],
+    ) -> Result<bool, Error> {
+        let mut tx = Transaction::new_with_payer(ixs, Some(&self.authority()));
+        tx.message.recent_blockhash = rpc.get_latest_blockhash()?;
+        let res = rpc.simulate_transaction_with_config(
+            &tx,
+            RpcSimulateTransactionConfig {
+                sig_verify: false,
+                ..Default::default()
+            },
+        )?;
+        Ok(matches!(
+            res.value.err,
+            Some(TransactionError::InstructionError(
+                _,
+                InstructionError::ComputationalBudgetExceeded
+            ))
+        ))
+    }
+
+    /// Splits `ixs` into batches that fit in a transaction. Batches are
+    /// only simulated to check their compute units if they have to be
+    /// split anyway, or if `atomic` needs to know that they fit; otherwise
+    /// a single transaction that runs out fails when sent.
+    fn split(
+        &self,
+        rpc: &RpcClient,
+        ixs: Vec<Instruction>,
+        atomic: bool,
+    ) -> Result<Vec<Vec<Instruction>>, Error> {
+        // Pack as many instructions as fit in size into each batch.
+        let mut todo = VecDeque::new();
+        let mut batch = Vec::new();
+        for ix in ixs {
+            batch.push(ix);
+            let size = self.tx_size(&batch);
+            if size > PACKET_DATA_SIZE {
+                if batch.len() == 1 {
+                    return Err(Error::TransactionTooLarge(size));
+                }
+                let ix = batch.pop().unwrap();
+                todo.push_back(std::mem::replace(&mut batch, vec![ix]));
+            }
+        }
+        if !batch.is_empty() {
+            todo.push_back(batch);
+        }
+
+        // Then halve the batches that run out of compute units.
+        let simulate = atomic || todo.len() > 1;
+        let mut batches = Vec::new();
+        while let Some(mut batch) = todo.pop_front() {
+            if simulate
+                && batch.len() > 1
+                && self.exceeds_compute(rpc, &batch)?
+            {
+                let rest = batch.split_off(batch.len() / 2);
+                todo.push_front(rest);
+                todo.push_front(batch);
+            } else {
+                batches.push(batch);
+            }
+        }
+        Ok(batches)
+    }
+
+    fn send_instructions(
+        &self,
+        rpc: &RpcClient,
+        ixs: &[Instruction],
+        opts: SendOptions,
+    ) -> Result<Sent, Error> {
+        let mut tx = Transaction::new_with_payer(ixs, Some(&self.authority()));
+        tx.message.recent_blockhash = rpc.get_latest_blockhash()?;
+        tx.signatures = vec![self.signer.sign_message(&tx.message_data())?];
+        let sig = rpc.send_transaction_with_config(
+            &tx,
+            RpcSendTransactionConfig {
+                skip_preflight: opts.skip_preflight,
+                preflight_commitment: opts.preflight_commitment,
+                ..Default::default()
+            },
+        )?;
+        let commitment = opts
+            .confirm_commitment
+            .map_or(self.confirm_commitment, |commitment| CommitmentConfig {
+                commitment,
+            });
+        let timeout = opts
+            .confirm_timeout
+            .map_or(self.confirm_timeout, Duration::from_secs);
+        let confirmed = match opts.confirm.unwrap_or(true) {
+            true => confirm(rpc, &self.rpc_gate, &sig, commitment, timeout)?,
+            false => false,
+        };
+        self.account_cache.clear();
+        let compute_units = match confirmed && opts.return_compute_units {
+            true => self.lookup_compute_units(rpc, &sig, commitment, timeout),
+            false => None,
+        };
+        Ok(Sent {
+            sig,
+            confirmed,
+            compute_units,
+        })
+    }
+
+    /// Compute units consumed by a transaction that reached `commitment`.
+    /// Transactions can only be fetched once confirmed, so one confirmed
+    /// at `processed` is waited on until then. The transaction has landed
+    /// by now, so a failed lookup is logged rather than failing the
+    /// request.
+    fn lookup_compute_units(
+        &self,
+        rpc: &RpcClient,
+        sig: &Signature,
+        commitment: CommitmentConfig,
+        timeout: Duration,
+    ) -> Option<u64> {
+        let commitment = match commitment.is_at_least_confirmed() {
+            true => commitment,
+            false => CommitmentConfig::confirmed(),
+        };
+        let r = match confirm(rpc, &self.rpc_gate, sig, commitment, timeout) {
+            Ok(true) => compute_units(rpc, sig, commitment),
+            Ok(false) => {
+                log::warn!(
+                    "{} wasn't confirmed to look up its compute units",
+                    sig
+                );
+                return None;
+            }
+            Err(e) => Err(e),
+        };
+        match r {
+            Ok(units) => Some(units),
+            Err(e) => {
+                log::warn!("Failed to look up compute units of {}: {}", sig, e);
+                None
+            }
+        }
+    }
+
+    /// Log messages of a transaction that landed, waited on until it's
+    /// confirmed as only confirmed transactions can be fetched.
+    pub fn transaction_logs(
+        &self,
+        sig: &Signature,
+    ) -> Result<Vec<String>, Error> {
+        let (rpc, commitment) = (self.rpc(), CommitmentConfig::confirmed());
+        confirm(&rpc, &self.rpc_gate, sig, commitment, self.confirm_timeout)?;
+        logs(&rpc, sig, commitment)
+    }
+
+    /// Returns a copy of the state which reads accounts at `c` instead of
+    /// the default read commitment.
+    pub fn with_read_commitment(&self, c: Option<CommitmentLevel>) -> Self {
+        let mut st = self.clone();
+        if let Some(commitment) = c {
+            st.read_commitment = CommitmentConfig { commitment };
+        }
+        st
+    }
+
+    /// Returns a copy of the state that reads the margin account of
+    /// `owner`, if given, instead of the authority's. Only meant for reads,
+    /// as transactions are still signed by the authority.
+    pub fn with_owner(&self, owner: Option<Pubkey>) -> Self {
+        let mut st = self.clone();
+        if let Some(owner) = owner {
+            st.zo_margin_key = margin_key(&owner);
+        }
+        st
+    }
+
+    pub fn account(&self, k: &Pubkey) -> Result<Option<Account>, Error> {
+        Ok(self
+            .rpc()
+            .get_account_with_commitment(k, self.read_commitment)?
+            .value)
+    }
+
+    fn account_data(&self, k: &Pubkey) -> Result<Vec<u8>, Error> {
+        self.account(k)?
+            .map(|a| a.data)
+            .ok_or_else(|| ClientError::AccountNotFound.into())
+    }
+
     pub fn market(&self, s: &str) -> Result<&zo::PerpMarketInfo, Error> {
         Ok(&self.zo_state.perp_markets[self.market_symbol_index(s)?])
     }
@@ -110,68 +656,72 @@ impl State {
         Ok(&self.zo_state.vaults[self.collateral_symbol_index(s)?])
     }
 
-    pub async fn oo(&self, s: &str) -> Result<Pubkey, Error> {
-        self.trader_accounts()
-            .await?
-            .1
-            .open_orders_agg
-            .iter()
-            .zip(self.zo_markets())
-            .find_map(|(oo, mkt)| match s == &String::from(mkt.symbol) {
-                true => {
-                    if oo.key == Pubkey::default() {
-                        None
-                    } else {
-                        Some(oo.key)
-                    }
-                }
-                false => None,
-            })
+    pub async fn oo_info(&self, s: &str) -> Result<zo::OpenOrdersInfo, Error> {
+        let i = self.market_symbol_index(s)?;
+        let (_, control) = self.trader_accounts().await?;
+        Some(control.open_orders_agg[i])
+            .filter(|oo| oo.key != Pubkey::default())
             .ok_or_else(|| Error::OpenOrdersNotFound(s.to_owned()))
     }
 
+    pub async fn oo(&self, s: &str) -> Result<Pubkey, Error> {
+        Ok(self.oo_info(s).await?.key)
+    }
+
     pub async fn dex_market(
         &self,
         s: &str,
     ) -> Result<zo::dex::ZoDexMarket, Error> {
-        let st = self.clone();
         let s = s.to_string();
-        tokio::task::spawn_blocking(move || {
-            st.rpc()
-                .get_account_data(&st.market(&s)?.dex_market)
-                .map_err(Into::into)
-                .map(|x| {
-                    zo::dex::ZoDexMarket::deserialize(&x).copied().unwrap()
-                })
+        self.with_rpc(move |st| {
+            st.account_data(&st.market(&s)?.dex_market).map(|x| {
+                zo::dex::ZoDexMarket::deserialize(&x).copied().unwrap()
+            })
         })
         .await
-        .unwrap()
     }
 
     pub async fn slab(&self, k: Pubkey) -> Result<zo::dex::Slab, Error> {
-        let st = self.clone();
-        tokio::task::spawn_blocking(move || {
-            st.rpc()
-                .get_account_data(&k)
-                .map_err(Into::into)
+        self.with_rpc(move |st| {
+            st.account_data(&k)
                 .map(|x| zo::dex::Slab::deserialize(&x).unwrap())
         })
         .await
-        .unwrap()
     }
 
     async fn program_account<T>(&self, k: &Pubkey) -> Result<T, Error>
     where
-        T: 'static
-            + anchor_client::anchor_lang::AccountDeserialize
-            + std::marker::Send,
+        T: 'static + Cached + std::marker::Send,
     {
-        let st = self.clone();
         let k = *k;
-        tokio::task::spawn_blocking(move || st.program().account::<T>(k))
-            .await
-            .unwrap()
-            .map_err(Error::from)
+        let c = self.read_commitment.commitment;
+        if let Some((_, a)) = self.account_cache.get(&k, c) {
+            return deserialize(Some(a));
+        }
+        let gen = self.account_cache.generation();
+        self.with_rpc(move |st| {
+            let res = st
+                .rpc()
+                .get_account_with_commitment(&k, st.read_commitment)?;
+            if let Some(a) = &res.value {
+                let slot = res.context.slot;
+                st.account_cache.insert(gen, k, c, slot, a.clone());
+            }
+            deserialize(res.value)
+        })
+        .await
+    }
+
+    /// Reads an SPL token account, or `None` if `k` isn't one.
+    pub async fn token_account(
+        &self,
+        k: Pubkey,
+    ) -> Result<Option<anchor_spl::token::TokenAccount>, Error> {
+        let a = self.with_rpc(move |st| st.account(&k)).await?;
+        Ok(a.filter(|a| a.owner == anchor_spl::token::ID)
+            .and_then(|a| {
+                AccountDeserialize::try_deserialize(&mut a.data.as_slice()).ok()
+            }))
     }
 
     pub fn zo_state(&self) -> &zo::State {
@@ -182,8 +732,27 @@ impl State {
         self.program_account(&self.zo_state.cache).await
     }
 
+    pub fn mark_history(&self) -> &MarkHistory {
+        &self.mark_history
+    }
+
+    /// Records the current mark prices in the mark history.
+    pub async fn sample_marks(&self) -> Result<(), Error> {
+        let cache = self.zo_cache().await?;
+        for (mkt, mark) in self.zo_markets().zip(cache.marks.iter()) {
+            self.mark_history
+                .record(String::from(mkt.symbol), mark.price.into());
+        }
+        Ok(())
+    }
+
     pub async fn zo_margin(&self) -> Result<zo::Margin, Error> {
-        self.program_account(&self.zo_margin_key).await
+        match self.program_account(&self.zo_margin_key).await {
+            Err(Error::AnchorClient(ClientError::AccountNotFound)) => {
+                Err(Error::MarginNotFound(self.zo_margin_key))
+            }
+            r => r,
+        }
     }
 
     pub async fn trader_accounts(
@@ -193,6 +762,54 @@ impl State {
         Ok((m, self.program_account::<zo::Control>(&m.control).await?))
     }
 
+    pub async fn snapshot(&self) -> Result<Snapshot, Error> {
+        // The control key of a margin account never changes, so reading
+        // it beforehand doesn't affect the consistency of the snapshot.
+        let control_key = self.zo_margin().await?.control;
+        let keys = [self.zo_state.cache, self.zo_margin_key, control_key];
+        let c = self.read_commitment.commitment;
+
+        // Cached accounts only make a snapshot if they're from one slot.
+        let cached: Option<Vec<_>> =
+            keys.iter().map(|k| self.account_cache.get(k, c)).collect();
+        if let Some(cached) = cached {
+            let slot = cached[0].0;
+            if cached.iter().all(|(s, _)| *s == slot) {
+                let [cache, margin, control]: [(u64, Account); 3] =
+                    cached.try_into().unwrap();
+                return Ok(Snapshot {
+                    slot,
+                    cache: deserialize(Some(cache.1))?,
+                    margin: deserialize(Some(margin.1))?,
+                    control: deserialize(Some(control.1))?,
+                });
+            }
+        }
+
+        let gen = self.account_cache.generation();
+        self.with_rpc(move |st| {
+            let res = st.rpc().get_multiple_accounts_with_commitment(
+                &keys,
+                st.read_commitment,
+            )?;
+            let slot = res.context.slot;
+            for (k, a) in keys.iter().zip(&res.value) {
+                if let Some(a) = a {
+                    st.account_cache.insert(gen, *k, c, slot, a.clone());
+                }
+            }
+            let [cache, margin, control]: [Option<Account>; 3] =
+                res.value.try_into().unwrap();
+            Ok(Snapshot {
+                slot,
+                cache: deserialize(cache)?,
+                margin: deserialize(margin)?,
+                control: deserialize(control)?,
+            })
+        })
+        .await
+    }
+
     pub fn zo_markets(&self) -> impl Iterator<Item = &zo::PerpMarketInfo> {
         self.zo_state()
             .perp_markets
@@ -0,0 +1,59 @@
+use crate::Error;
+use actix_web::{dev::Payload, FromRequest, HttpRequest};
+use futures::future::{ready, Ready};
+use std::{fmt, ops::Deref, str::FromStr};
+
+/// Longest symbol the program can store.
+const MAX_LEN: usize = 24;
+
+/// Symbol of a market or collateral, trimmed and upper-cased. Parsing only
+/// rejects what can't be a symbol at all; whether it exists is up to the
+/// state to tell.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Symbol(String);
+
+impl FromStr for Symbol {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let s = s.trim();
+        let valid = !s.is_empty()
+            && s.len() <= MAX_LEN
+            && s.chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'));
+        match valid {
+            true => Ok(Self(s.to_ascii_uppercase())),
+            false => Err(Error::InvalidSymbol(s.to_owned())),
+        }
+    }
+}
+
+impl Deref for Symbol {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<Symbol> for String {
+    fn from(s: Symbol) -> Self {
+        s.0
+    }
+}
+
+/// Extracts the `{symbol}` segment of the route.
+impl FromRequest for Symbol {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(req.match_info().get("symbol").unwrap_or_default().parse())
+    }
+}